@@ -20,6 +20,8 @@ use json::{self};
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
 
 use crate::eval_context::Config;
 
@@ -43,27 +45,135 @@ pub(crate) fn get_library_names(config: &Config) -> Result<Vec<String>> {
     }
 }
 
-pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Result<()> {
-    std::fs::write(
-        config.crate_dir.join("Cargo.toml"),
-        format!(
-            r#"
-    [package]
-    name = "evcxr_dummy_validate_dep"
-    version = "0.0.1"
-    edition = "2021"
+/// Returns the number of packages that need to be built in order to build the crate rooted at
+/// the specified path for `target`, including that crate itself.
+pub(crate) fn get_package_count(config: &Config, target: &str) -> Result<usize> {
+    let output = config
+        .cargo_command("metadata")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--filter-platform")
+        .arg(target)
+        .output()
+        .with_context(|| "Error running cargo metadata")?;
+    if output.status.success() {
+        package_count_from_metadata(std::str::from_utf8(&output.stdout)?)
+    } else {
+        bail!(
+            "cargo metadata failed with output:\n{}{}",
+            std::str::from_utf8(&output.stdout)?,
+            std::str::from_utf8(&output.stderr)?,
+        )
+    }
+}
 
-    [lib]
-    path = "lib.rs"
+/// A direct dependency of our crate, as resolved by cargo.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ResolvedDependency {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) features: Vec<String>,
+    /// All the features that the package defines, other than "default".
+    pub(crate) available_features: Vec<String>,
+    /// The features that the package enables by default.
+    pub(crate) default_features: Vec<String>,
+    /// Where the package came from. For registry and git dependencies, this is cargo's source ID.
+    /// For path dependencies, it's the directory containing the package.
+    pub(crate) source: String,
+}
 
-    [dependencies]
-    {} = {}
-    "#,
-            dep, dep_config
-        ),
-    )?;
+/// Runs `cargo metadata` on the crate that user code is compiled into and returns its direct
+/// dependencies, together with the versions and features that cargo selected for them.
+pub(crate) fn get_resolved_dependencies(config: &Config) -> Result<Vec<ResolvedDependency>> {
+    let output = config
+        .cargo_command("metadata")
+        .arg("--format-version")
+        .arg("1")
+        .output()
+        .with_context(|| "Error running cargo metadata")?;
+    if output.status.success() {
+        resolved_dependencies_from_metadata(std::str::from_utf8(&output.stdout)?)
+    } else {
+        bail!(
+            "cargo metadata failed with output:\n{}{}",
+            std::str::from_utf8(&output.stdout)?,
+            std::str::from_utf8(&output.stderr)?,
+        )
+    }
+}
+
+/// Runs `cargo tree` on the crate that user code is compiled into and returns what it printed.
+pub(crate) fn get_dependency_tree(config: &Config) -> Result<String> {
+    let output = config
+        .cargo_command("tree")
+        .output()
+        .with_context(|| "Error running cargo tree")?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        bail!(
+            "cargo tree failed with output:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+    }
+}
+
+/// A crate on the local filesystem, as loaded by `:load_crate`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LocalCrate {
+    pub(crate) name: String,
+    /// The name by which the crate's library can be referred to from Rust code, if it has one.
+    pub(crate) lib_name: Option<String>,
+    /// The directory containing the crate's Cargo.toml.
+    pub(crate) dir: String,
+    /// The crate's normal (non-dev, non-build) dependencies as (name, config) pairs, suitable for
+    /// passing to `ContextState::add_dep`.
+    pub(crate) dependencies: Vec<(String, String)>,
+    /// Names of dependencies that we couldn't represent as a `:dep`. e.g. optional or
+    /// platform-specific dependencies.
+    pub(crate) skipped_dependencies: Vec<String>,
+}
+
+/// Runs `cargo metadata` on the package whose manifest is in `dir` and extracts its name, library
+/// name and those of its dependencies that can be expressed as `:dep` commands.
+pub(crate) fn get_local_crate(config: &Config, dir: &Path) -> Result<LocalCrate> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to find crate directory {:?}", dir))?;
+    let manifest_path = dir.join("Cargo.toml");
+    let output = config
+        .cargo_command("metadata")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--no-deps")
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .output()
+        .with_context(|| "Error running cargo metadata")?;
+    if output.status.success() {
+        local_crate_from_metadata(std::str::from_utf8(&output.stdout)?, &manifest_path)
+    } else {
+        bail!(
+            "cargo metadata failed with output:\n{}{}",
+            std::str::from_utf8(&output.stdout)?,
+            std::str::from_utf8(&output.stderr)?,
+        )
+    }
+}
+
+/// The directory, within our workspace directory, in which we create a crate in order to validate
+/// dependencies before adding them to the main crate. This crate has its own `[workspace]` table,
+/// so that a bad dependency can't break the main workspace.
+const VALIDATE_DEP_DIR: &str = "evcxr_validate_dep";
+
+pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Result<()> {
+    let validate_dir = write_validate_dep_crate(dep, dep_config, config)?;
     let mut cmd = config.cargo_command("metadata");
-    let output = cmd.arg("-q").arg("--format-version=1").output()?;
+    let output = cmd
+        .current_dir(&validate_dir)
+        .arg("-q")
+        .arg("--format-version=1")
+        .output()?;
     if output.status.success() {
         Ok(())
     } else {
@@ -81,18 +191,140 @@ pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Resu
                 message.push(line.to_owned());
             }
         }
+        if config.offline_mode {
+            message.push(offline_hint(dep));
+        }
         bail!(message.join("\n"));
     }
 }
 
+/// Resolves a dependency that hasn't necessarily been added yet, returning the version and
+/// features that cargo would select for it.
+pub(crate) fn resolve_dep(
+    dep: &str,
+    dep_config: &str,
+    config: &Config,
+) -> Result<Option<ResolvedDependency>> {
+    let validate_dir = write_validate_dep_crate(dep, dep_config, config)?;
+    let output = config
+        .cargo_command("metadata")
+        .current_dir(&validate_dir)
+        .arg("-q")
+        .arg("--format-version=1")
+        .output()
+        .with_context(|| "Error running cargo metadata")?;
+    if output.status.success() {
+        Ok(
+            resolved_dependencies_from_metadata(std::str::from_utf8(&output.stdout)?)?
+                .into_iter()
+                .next(),
+        )
+    } else {
+        bail!(
+            "cargo metadata failed with output:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+    }
+}
+
+/// Writes a crate with just the specified dependency and returns its directory.
+fn write_validate_dep_crate(dep: &str, dep_config: &str, config: &Config) -> Result<PathBuf> {
+    let validate_dir = config.workspace_dir.join(VALIDATE_DEP_DIR);
+    std::fs::create_dir_all(&validate_dir)?;
+    std::fs::write(
+        validate_dir.join("Cargo.toml"),
+        format!(
+            r#"
+    [package]
+    name = "evcxr_dummy_validate_dep"
+    version = "0.0.1"
+    edition = "2021"
+
+    [lib]
+    path = "lib.rs"
+
+    [workspace]
+
+    [dependencies]
+    {} = {}
+    {}"#,
+            dep,
+            dep_config,
+            config.patch_tables()
+        ),
+    )?;
+    Ok(validate_dir)
+}
+
+/// The directory, within our workspace directory, in which we create a crate in order to check
+/// that cargo can parse new cargo configuration before we use it for the main workspace.
+const VALIDATE_CARGO_CONFIG_DIR: &str = "evcxr_validate_cargo_config";
+
+/// Checks that cargo accepts `contents` as the contents of a `.cargo/config.toml`.
+pub(crate) fn validate_cargo_config(contents: &str, config: &Config) -> Result<()> {
+    let validate_dir = config.workspace_dir.join(VALIDATE_CARGO_CONFIG_DIR);
+    std::fs::create_dir_all(validate_dir.join(".cargo"))?;
+    std::fs::write(
+        validate_dir.join("Cargo.toml"),
+        r#"
+    [package]
+    name = "evcxr_dummy_validate_cargo_config"
+    version = "0.0.1"
+    edition = "2021"
+
+    [lib]
+    path = "lib.rs"
+
+    [workspace]
+    "#,
+    )?;
+    std::fs::write(validate_dir.join(".cargo").join("config.toml"), contents)?;
+    let output = config
+        .cargo_command("metadata")
+        .current_dir(&validate_dir)
+        .arg("-q")
+        .arg("--no-deps")
+        .arg("--format-version=1")
+        .output()
+        .with_context(|| "Error running cargo metadata")?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        bail!(
+            "Invalid cargo configuration:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )
+    }
+}
+
+/// Returns a hint for when a dependency can't be resolved while offline, listing what's available.
+fn offline_hint(dep: &str) -> String {
+    match crate::local_registry::find(dep) {
+        Some(krate) => format!(
+            "Versions of `{}` available offline: {}",
+            krate.name,
+            krate.versions.join(", ")
+        ),
+        None => format!(
+            "`{}` isn't in the local registry cache, so can't be used while offline. \
+             Try `:search` to find crates that are available.",
+            dep
+        ),
+    }
+}
+
 fn library_names_from_metadata(metadata: &str) -> Result<Vec<String>> {
     let metadata = json::parse(metadata)?;
     let mut direct_dependencies = Vec::new();
     let mut crate_to_library_names = HashMap::new();
-    if let (JsonValue::Array(packages), Some(main_crate_id)) = (
-        &metadata["packages"],
-        metadata["workspace_members"][0].as_str(),
-    ) {
+    // When run from within a workspace member, the resolve root is that member. Fall back to the
+    // first workspace member for older versions of cargo.
+    let main_crate_id = metadata["resolve"]["root"]
+        .as_str()
+        .or_else(|| metadata["workspace_members"][0].as_str());
+    if let (JsonValue::Array(packages), Some(main_crate_id)) =
+        (&metadata["packages"], main_crate_id)
+    {
         for package in packages {
             if let (Some(package_name), Some(id)) =
                 (package["name"].as_str(), package["id"].as_str())
@@ -130,12 +362,195 @@ fn library_names_from_metadata(metadata: &str) -> Result<Vec<String>> {
     Ok(library_names)
 }
 
+fn resolved_dependencies_from_metadata(metadata: &str) -> Result<Vec<ResolvedDependency>> {
+    let metadata = json::parse(metadata)?;
+    let root_id = metadata["resolve"]["root"]
+        .as_str()
+        .or_else(|| metadata["workspace_members"][0].as_str());
+    let mut packages_by_id = HashMap::new();
+    for package in metadata["packages"].members() {
+        if let Some(id) = package["id"].as_str() {
+            packages_by_id.insert(id, package);
+        }
+    }
+    let mut nodes_by_id = HashMap::new();
+    for node in metadata["resolve"]["nodes"].members() {
+        if let Some(id) = node["id"].as_str() {
+            nodes_by_id.insert(id, node);
+        }
+    }
+    let mut dependencies = Vec::new();
+    if let Some(root_node) = root_id.and_then(|id| nodes_by_id.get(id)) {
+        for dependency_id in root_node["dependencies"]
+            .members()
+            .filter_map(|id| id.as_str())
+        {
+            let (package, node) = match (
+                packages_by_id.get(dependency_id),
+                nodes_by_id.get(dependency_id),
+            ) {
+                (Some(package), Some(node)) => (package, node),
+                _ => continue,
+            };
+            let source = match package["source"].as_str() {
+                Some(source) => source.to_owned(),
+                None => package["manifest_path"]
+                    .as_str()
+                    .map(|manifest_path| {
+                        std::path::Path::new(manifest_path)
+                            .parent()
+                            .unwrap_or_else(|| std::path::Path::new(manifest_path))
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                    .unwrap_or_default(),
+            };
+            dependencies.push(ResolvedDependency {
+                name: package["name"].as_str().unwrap_or_default().to_owned(),
+                version: package["version"].as_str().unwrap_or_default().to_owned(),
+                features: node["features"]
+                    .members()
+                    .filter_map(|feature| feature.as_str())
+                    .map(str::to_owned)
+                    .collect(),
+                available_features: package["features"]
+                    .entries()
+                    .map(|(feature, _)| feature)
+                    .filter(|feature| *feature != "default")
+                    .map(str::to_owned)
+                    .collect(),
+                default_features: default_features(package),
+                source,
+            });
+        }
+    }
+    dependencies.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(dependencies)
+}
+
+/// Returns the features enabled by `package`'s default feature. Entries that enable features of
+/// dependencies or optional dependencies (e.g. "foo/bar" or "dep:foo") aren't features of the
+/// package itself, so are skipped.
+fn default_features(package: &JsonValue) -> Vec<String> {
+    package["features"]["default"]
+        .members()
+        .filter_map(|feature| feature.as_str())
+        .filter(|feature| !feature.contains('/') && !feature.starts_with("dep:"))
+        .map(str::to_owned)
+        .collect()
+}
+
+fn local_crate_from_metadata(metadata: &str, manifest_path: &Path) -> Result<LocalCrate> {
+    let metadata = json::parse(metadata)?;
+    let package = match metadata["packages"]
+        .members()
+        .find(|package| package["manifest_path"].as_str().map(Path::new) == Some(manifest_path))
+    {
+        Some(package) => package,
+        None => bail!("No package found in {:?}", manifest_path),
+    };
+    let lib_name = package["targets"]
+        .members()
+        .find(|target| {
+            target["kind"]
+                .members()
+                .any(|kind| kind == "lib" || kind == "rlib" || kind == "proc-macro")
+        })
+        .and_then(|target| target["name"].as_str())
+        .map(|name| name.replace('-', "_"));
+    let mut dependencies = Vec::new();
+    let mut skipped_dependencies = Vec::new();
+    for dependency in package["dependencies"].members() {
+        // We only want normal dependencies. Dev and build dependencies aren't relevant.
+        if !dependency["kind"].is_null() {
+            continue;
+        }
+        match dependency_spec(dependency) {
+            Some(spec) => dependencies.push(spec),
+            None => skipped_dependencies
+                .push(dependency["name"].as_str().unwrap_or_default().to_owned()),
+        }
+    }
+    Ok(LocalCrate {
+        name: package["name"].as_str().unwrap_or_default().to_owned(),
+        lib_name,
+        dir: manifest_path
+            .parent()
+            .unwrap_or(manifest_path)
+            .to_string_lossy()
+            .into_owned(),
+        dependencies,
+        skipped_dependencies,
+    })
+}
+
+/// Converts a dependency from cargo metadata into a (name, config) pair as would be given to `:dep`.
+/// Returns None if we can't represent the dependency.
+fn dependency_spec(dependency: &JsonValue) -> Option<(String, String)> {
+    if dependency["optional"].as_bool() == Some(true) || !dependency["target"].is_null() {
+        return None;
+    }
+    let name = dependency["name"].as_str()?;
+    let source = dependency["source"].as_str();
+    let mut fields = Vec::new();
+    if let Some(path) = dependency["path"].as_str() {
+        fields.push(format!("path = \"{}\"", path.replace('\\', "\\\\")));
+    } else if let Some(git) = source.and_then(|source| source.strip_prefix("git+")) {
+        let git = git.split('#').next().unwrap_or(git);
+        let (url, query) = git.split_once('?').unwrap_or((git, ""));
+        fields.push(format!("git = \"{}\"", url));
+        for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            if key == "branch" || key == "tag" || key == "rev" {
+                fields.push(format!("{} = \"{}\"", key, value));
+            }
+        }
+    } else if source.is_none() {
+        // A path dependency, but we're using a version of cargo that doesn't tell us the path.
+        return None;
+    }
+    let req = dependency["req"].as_str().unwrap_or("*");
+    if req != "*" || fields.is_empty() {
+        fields.push(format!("version = \"{}\"", req));
+    }
+    if dependency["uses_default_features"].as_bool() == Some(false) {
+        fields.push("default-features = false".to_owned());
+    }
+    let features: Vec<_> = dependency["features"]
+        .members()
+        .filter_map(|feature| feature.as_str())
+        .map(|feature| format!("\"{}\"", feature))
+        .collect();
+    if !features.is_empty() {
+        fields.push(format!("features = [{}]", features.join(", ")));
+    }
+    let key = match dependency["rename"].as_str() {
+        Some(rename) => {
+            fields.push(format!("package = \"{}\"", name));
+            rename
+        }
+        None => name,
+    };
+    Some((key.to_owned(), format!("{{ {} }}", fields.join(", "))))
+}
+
+fn package_count_from_metadata(metadata: &str) -> Result<usize> {
+    let metadata = json::parse(metadata)?;
+    Ok(metadata["resolve"]["nodes"].len())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::eval_context::Config;
 
+    use super::default_features;
+    use super::dependency_spec;
     use super::get_library_names;
     use super::library_names_from_metadata;
+    use super::local_crate_from_metadata;
+    use super::package_count_from_metadata;
+    use super::resolved_dependencies_from_metadata;
+    use super::LocalCrate;
+    use super::ResolvedDependency;
     use anyhow::Result;
     use std::path::Path;
     use tempfile;
@@ -148,6 +563,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_package_count_from_metadata() {
+        assert_eq!(
+            package_count_from_metadata(include_str!("testdata/sample_metadata.json")).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_resolved_dependencies_from_metadata() {
+        assert_eq!(
+            resolved_dependencies_from_metadata(include_str!("testdata/sample_metadata.json"))
+                .unwrap(),
+            vec![ResolvedDependency {
+                name: "crate1".to_owned(),
+                version: "0.0.1".to_owned(),
+                features: vec![],
+                available_features: vec![],
+                default_features: vec![],
+                source: "/var/folders/nz/vv4_9tw56nv9k3tkvyszvwg80000gn/T/.tmpAn1ccQ/crate1"
+                    .to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_default_features() {
+        let package = json::parse(
+            r#"{
+                "features": {
+                    "default": ["std", "serde/std", "dep:rand"],
+                    "std": [],
+                    "alloc": []
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(default_features(&package), vec!["std".to_owned()]);
+    }
+
+    #[test]
+    fn test_local_crate_from_metadata() {
+        let dir = "/private/var/folders/nz/vv4_9tw56nv9k3tkvyszvwg80000gn/T/.tmpAn1ccQ/crate2";
+        assert_eq!(
+            local_crate_from_metadata(
+                include_str!("testdata/sample_metadata.json"),
+                &Path::new(dir).join("Cargo.toml")
+            )
+            .unwrap(),
+            LocalCrate {
+                name: "crate2".to_owned(),
+                lib_name: Some("crate2".to_owned()),
+                dir: dir.to_owned(),
+                dependencies: vec![],
+                // Our sample metadata was produced by a version of cargo that doesn't report the
+                // path of path dependencies.
+                skipped_dependencies: vec!["crate1".to_owned()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_dependency_spec() {
+        assert_eq!(
+            dependency_spec(
+                &json::parse(
+                    r#"{
+                    "name": "serde",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "req": "^1.0.100",
+                    "kind": null,
+                    "rename": null,
+                    "optional": false,
+                    "uses_default_features": false,
+                    "features": ["derive", "std"],
+                    "target": null
+                }"#
+                )
+                .unwrap()
+            ),
+            Some((
+                "serde".to_owned(),
+                r#"{ version = "^1.0.100", default-features = false, features = ["derive", "std"] }"#
+                    .to_owned()
+            ))
+        );
+        assert_eq!(
+            dependency_spec(
+                &json::parse(
+                    r#"{
+                    "name": "foo",
+                    "source": "git+https://github.com/example/foo?branch=dev",
+                    "req": "*",
+                    "kind": null,
+                    "rename": "bar",
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null
+                }"#
+                )
+                .unwrap()
+            ),
+            Some((
+                "bar".to_owned(),
+                r#"{ git = "https://github.com/example/foo", branch = "dev", package = "foo" }"#
+                    .to_owned()
+            ))
+        );
+    }
+
     fn create_crate(path: &Path, name: &str, deps: &str) -> Result<()> {
         let src_dir = path.join("src");
         std::fs::create_dir_all(&src_dir)?;
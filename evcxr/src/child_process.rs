@@ -49,6 +49,9 @@ impl ChildProcess {
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped());
+        // Otherwise pressing Ctrl-C while we're building would kill our subprocess and with it, all
+        // the user's variables.
+        crate::module::start_in_new_process_group(&mut command);
         ChildProcess::new_internal(
             Arc::new(Mutex::new(command)),
             None,
@@ -104,6 +104,11 @@ fn num_lines(code: &str) -> usize {
     code.chars().filter(|ch| *ch == '\n').count()
 }
 
+/// Returns whether `line` is an outer doc comment. Four or more slashes make an ordinary comment.
+fn is_doc_comment(line: &str) -> bool {
+    line.starts_with("///") && !line.starts_with("////")
+}
+
 pub(crate) fn count_columns(code: &str) -> usize {
     // We use characters here, not graphemes because seems to be how columns are counted by the rust
     // compiler, which we need to be consistent with. It also works well with the inline error
@@ -197,6 +202,7 @@ impl CodeBlock {
         let mut lines = user_code.lines();
         let mut line_number = 1;
         let mut current_line = lines.next().unwrap_or(user_code);
+        let mut doc_comment_start = None;
 
         for (command_line_offset, line) in user_code.lines().enumerate() {
             // We only accept commands up until the first non-command.
@@ -210,12 +216,20 @@ impl CodeBlock {
                     }),
                     line,
                 );
+                doc_comment_start = None;
+            } else if is_doc_comment(line) {
+                // Doc comments belong to whatever follows them. If that's Rust code, then the code
+                // starts from the first of these lines, otherwise they're ignored like any other
+                // comment.
+                doc_comment_start.get_or_insert(line);
             } else if line.starts_with(r"//") || line.trim().is_empty() {
                 // Ignore blank lines, otherwise we can't have blank lines before :dep commands.
                 // We also ignore lines that start with //, because those are line comments.
             } else {
                 // Anything else, we treat as Rust code to be executed. Since we don't accept commands after Rust code, we're done looking for commands.
-                let non_command_start_byte = line.as_ptr() as usize - user_code.as_ptr() as usize;
+                let code_start = doc_comment_start.unwrap_or(line);
+                let non_command_start_byte =
+                    code_start.as_ptr() as usize - user_code.as_ptr() as usize;
                 for OriginalUserCode {
                     code,
                     start_byte,
@@ -416,4 +430,22 @@ mod test {
             user_code
         );
     }
+
+    #[test]
+    fn doc_comments_before_commands() {
+        let user_code =
+            "/// Not attached to anything\n:dep foo = \"1.0\"\n\n/// Adds one\nfn f() {}";
+        let (code, _) = CodeBlock::from_original_user_code(user_code);
+        assert_eq!(code.segments.len(), 2);
+        assert!(matches!(
+            &code.segments[0].kind,
+            CodeKind::Command(call) if call.command == ":dep"
+        ));
+        assert_eq!(code.segments[1].code.trim(), "/// Adds one\nfn f() {}");
+        if let CodeKind::OriginalUserCode(meta) = &code.segments[1].kind {
+            assert_eq!(meta.start_line, 4);
+        } else {
+            panic!("Unexpected kind {:?}", code.segments[1].kind);
+        }
+    }
 }
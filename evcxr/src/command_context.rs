@@ -13,15 +13,21 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use crate::cargo_metadata::LocalCrate;
+use crate::cargo_metadata::ResolvedDependency;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::{self};
 use crate::crash_guard::CrashGuard;
+use crate::crate_config;
+use crate::emitted_code;
+use crate::emitted_code::EmitKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
@@ -29,8 +35,15 @@ use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::local_registry;
+use crate::local_registry::CachedCrate;
+use crate::module::BuildCancellationHandle;
 use crate::rust_analyzer::Completion;
+use crate::rust_analyzer::CompletionKind;
 use crate::rust_analyzer::Completions;
+use crate::rust_analyzer::Hover;
+use crate::rust_analyzer::SignatureHelp;
+use crate::rust_analyzer::TypeName;
 use crate::EvalContext;
 use crate::EvalContextOutputs;
 use crate::EvalOutputs;
@@ -43,6 +56,10 @@ pub struct CommandContext {
     print_timings: bool,
     eval_context: EvalContext,
     last_errors: Vec<CompilationError>,
+    /// The features of dependencies that we've offered as completions, keyed by crate name and
+    /// config, so that we don't run cargo each time the user presses tab. `None` if the dependency
+    /// couldn't be resolved.
+    dep_features: HashMap<(String, String), Option<Vec<String>>>,
 }
 
 impl CommandContext {
@@ -57,6 +74,7 @@ impl CommandContext {
             print_timings: false,
             eval_context,
             last_errors: Vec::new(),
+            dep_features: HashMap::new(),
         }
     }
 
@@ -71,6 +89,9 @@ impl CommandContext {
     }
 
     pub fn check(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
+        if parse_proc_macro_cell(code).is_some() {
+            return Ok(Vec::new());
+        }
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
         let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
         if !errors.is_empty() {
@@ -82,10 +103,30 @@ impl CommandContext {
         self.eval_context.check(non_command_code, state, &code_info)
     }
 
+    /// Like `check`, but uses rust-analyzer's diagnostics rather than running cargo check. This
+    /// typically takes milliseconds rather than seconds, but finds fewer problems, so it's intended
+    /// to be used as a first pass, with `check` following.
+    pub fn fast_check(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
+        if parse_proc_macro_cell(code).is_some() {
+            return Ok(Vec::new());
+        }
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
+        if !errors.is_empty() {
+            return Ok(errors);
+        }
+        self.eval_context
+            .analyzer_check(non_command_code, state, &code_info)
+    }
+
     pub fn process_handle(&self) -> Arc<Mutex<std::process::Child>> {
         self.eval_context.process_handle()
     }
 
+    pub fn build_cancellation_handle(&self) -> BuildCancellationHandle {
+        self.eval_context.build_cancellation_handle()
+    }
+
     pub fn variables_and_types(&self) -> impl Iterator<Item = (&str, &str)> {
         self.eval_context.variables_and_types()
     }
@@ -119,7 +160,11 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 to_run, state
             );
         });
+        let cancellation = self.build_cancellation_handle();
+        cancellation.start_building();
         let result = self.execute_with_callbacks_internal(to_run, callbacks);
+        // If we got as far as running the code, we'll already have stopped.
+        let _ = cancellation.stop_building();
         guard.disarm();
         result
     }
@@ -132,6 +177,19 @@ Panic detected. Here's some useful information if you're filing a bug report.
         use std::time::Instant;
         let mut eval_outputs = EvalOutputs::new();
         let start = Instant::now();
+        if let Some((name, source)) = parse_proc_macro_cell(to_run) {
+            let mut state = self.eval_context.state();
+            self.eval_context
+                .define_proc_macro(name, source, &mut state, callbacks)?;
+            let (user_code, code_info) = CodeBlock::from_original_user_code("");
+            self.eval_context
+                .eval_with_callbacks(user_code, state, &code_info, callbacks)?;
+            return text_output(format!("Defined proc macro crate `{}`", name));
+        }
+        if let Some(code) = parse_expand_cell(to_run) {
+            let state = self.eval_context.state();
+            return self.expand_macros(code, &state);
+        }
         let mut state = self.eval_context.state();
         let mut non_command_code = CodeBlock::new();
         let (user_code, code_info) = CodeBlock::from_original_user_code(to_run);
@@ -180,6 +238,9 @@ Panic detected. Here's some useful information if you're filing a bug report.
     /// completions. It also assumes exclusive access to those resources. However there should be
     /// any visible side effects.
     pub fn completions(&mut self, src: &str, position: usize) -> Result<Completions> {
+        if parse_proc_macro_cell(src).is_some() {
+            return Ok(Completions::default());
+        }
         let (user_code, code_info) = CodeBlock::from_original_user_code(src);
         if let Some((segment, offset)) = user_code.command_containing_user_offset(position) {
             return self.command_completions(segment, offset, position);
@@ -189,6 +250,109 @@ Panic detected. Here's some useful information if you're filing a bug report.
             .completions(non_command_code, state, &code_info.nodes, position)
     }
 
+    /// Returns information about the item at `position` in `src`, such as its signature and
+    /// documentation. `position` should be a byte offset.
+    pub fn hover(&mut self, src: &str, position: usize) -> Result<Option<Hover>> {
+        if parse_proc_macro_cell(src).is_some() {
+            return Ok(None);
+        }
+        let (user_code, code_info) = CodeBlock::from_original_user_code(src);
+        if user_code.command_containing_user_offset(position).is_some() {
+            return Ok(None);
+        }
+        let (non_command_code, state, _errors) = self.prepare_for_analysis(user_code)?;
+        self.eval_context
+            .hover(non_command_code, state, &code_info.nodes, position)
+    }
+
+    /// Returns the signature of the function or method call whose arguments contain `position` in
+    /// `src`. `position` should be a byte offset.
+    pub fn signature_help(&mut self, src: &str, position: usize) -> Result<Option<SignatureHelp>> {
+        if parse_proc_macro_cell(src).is_some() {
+            return Ok(None);
+        }
+        let (user_code, code_info) = CodeBlock::from_original_user_code(src);
+        if user_code.command_containing_user_offset(position).is_some() {
+            return Ok(None);
+        }
+        let (non_command_code, state, _errors) = self.prepare_for_analysis(user_code)?;
+        self.eval_context
+            .signature_help(non_command_code, state, &code_info.nodes, position)
+    }
+
+    /// Returns what each macro call in `code` expands to, without running anything.
+    fn expand_macros(&mut self, code: &str, state: &ContextState) -> Result<EvalOutputs, Error> {
+        let offsets = macro_call_offsets(code);
+        if offsets.is_empty() {
+            bail!("No macro calls found");
+        }
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let expansions = self.eval_context.expand_macros(
+            user_code,
+            state.clone(),
+            &code_info.nodes,
+            &offsets,
+        )?;
+        let mut out = String::new();
+        for (offset, expansion) in offsets.iter().zip(expansions) {
+            use std::fmt::Write;
+            let line = code[..*offset].matches('\n').count() + 1;
+            match expansion {
+                Some(expansion) => writeln!(
+                    out,
+                    "// line {}: {}!\n{}",
+                    line,
+                    expansion.name,
+                    expansion.expansion.trim_end()
+                )?,
+                None => writeln!(out, "// line {}: couldn't expand macro", line)?,
+            }
+        }
+        text_output(out.trim_end())
+    }
+
+    /// Recompiles the items defined so far and returns the `kind` code that rustc generates for the
+    /// function `name`.
+    fn emitted_function(
+        &mut self,
+        name: Option<&str>,
+        kind: EmitKind,
+        state: &ContextState,
+    ) -> Result<EvalOutputs, Error> {
+        let name = match name.map(str::trim) {
+            Some(name) if !name.is_empty() => name,
+            _ => bail!("Expected the name of a function"),
+        };
+        let output = self.eval_context.emit(state, kind)?;
+        let functions = emitted_code::extract_functions(kind, &output, name);
+        if functions.is_empty() {
+            bail!(
+                "No function named `{}` was found. Generic functions only appear once they're \
+                 used with concrete types.",
+                name
+            );
+        }
+        text_output(functions.join("\n\n"))
+    }
+
+    /// Returns hover information, including documentation, for the item at `path`.
+    fn item_docs(&mut self, path: &str, state: &ContextState) -> Result<Option<Hover>, Error> {
+        // Most items can be referred to by a use statement. Associated items such as methods can't,
+        // so for those, we fall back to referring to them from an expression.
+        for code in [format!("use {};", path), format!("let _ = {};", path)] {
+            let (user_code, code_info) = CodeBlock::from_original_user_code(&code);
+            // Hover over the last character of the path, which is within its last segment.
+            let position = code.find(path).unwrap_or_default() + path.len() - 1;
+            if let Some(hover) =
+                self.eval_context
+                    .hover(user_code, state.clone(), &code_info.nodes, position)?
+            {
+                return Ok(Some(hover));
+            }
+        }
+        Ok(None)
+    }
+
     fn prepare_for_analysis(
         &mut self,
         user_code: CodeBlock,
@@ -212,12 +376,15 @@ Panic detected. Here's some useful information if you're filing a bug report.
     }
 
     fn command_completions(
-        &self,
+        &mut self,
         segment: &Segment,
         offset: usize,
         full_position: usize,
     ) -> Result<Completions> {
         let existing = &segment.code[0..offset];
+        if let Some(completions) = self.dep_feature_completions(existing, full_position) {
+            return Ok(completions);
+        }
         let mut completions = Completions {
             start_offset: full_position - offset,
             end_offset: full_position,
@@ -225,14 +392,64 @@ Panic detected. Here's some useful information if you're filing a bug report.
         };
         for cmd in Self::commands_by_name().keys() {
             if cmd.starts_with(existing) {
-                completions.completions.push(Completion {
-                    code: (*cmd).to_owned(),
-                })
+                completions
+                    .completions
+                    .push(Completion::new((*cmd).to_owned(), CompletionKind::Command))
             }
         }
         Ok(completions)
     }
 
+    /// If `existing` is a `:dep` command that's part way through listing features, returns
+    /// completions for the features of the crate being added.
+    fn dep_feature_completions(
+        &mut self,
+        existing: &str,
+        full_position: usize,
+    ) -> Option<Completions> {
+        use regex::Regex;
+        static FEATURES_RE: OnceCell<Regex> = OnceCell::new();
+        let features_re = FEATURES_RE.get_or_init(|| {
+            Regex::new(r"^:dep +([^= ]+) *= *(\{.*?)(?-u:\b)features *= *\[([^\]]*)$").unwrap()
+        });
+        let captures = features_re.captures(existing)?;
+        let features_so_far = &captures[3];
+        // We only complete within a quoted string.
+        if features_so_far.matches('"').count() % 2 == 0 {
+            return None;
+        }
+        let partial_feature = features_so_far.rsplit('"').next().unwrap_or_default();
+        // Resolve the dependency using whatever config precedes the features.
+        let config_so_far = captures[2].trim_end().trim_end_matches(',');
+        let dep_config = if config_so_far == "{" {
+            "{ version = \"*\" }".to_owned()
+        } else {
+            format!("{} }}", config_so_far)
+        };
+        let mut state = self.eval_context.state();
+        let features = self
+            .dep_features
+            .entry((captures[1].to_owned(), dep_config))
+            .or_insert_with_key(|(dep, dep_config)| {
+                // Resolving a dependency from a registry may need network access, which we try to
+                // avoid in offline mode.
+                if state.offline_mode() && !crate_config::is_path_dep(dep_config) {
+                    return None;
+                }
+                Some(state.resolve_dep(dep, dep_config).ok()??.available_features)
+            })
+            .as_ref()?;
+        Some(Completions {
+            start_offset: full_position - partial_feature.len(),
+            end_offset: full_position,
+            completions: features
+                .iter()
+                .filter(|feature| feature.starts_with(partial_feature))
+                .map(|feature| Completion::new(feature.clone(), CompletionKind::Other))
+                .collect(),
+        })
+    }
+
     fn load_config(&mut self, quiet: bool) -> Result<EvalOutputs, Error> {
         let mut outputs = EvalOutputs::new();
         if let Some(config_dir) = crate::config_dir() {
@@ -406,6 +623,294 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 "Add dependency. e.g. :dep regex = \"1.0\"",
                 |_ctx, state, args| process_dep_command(state, args),
             ),
+            AvailableCommand::new(
+                ":search",
+                "Search for crates that have already been downloaded, so can be used offline",
+                |_ctx, _state, args| {
+                    let term = match args.as_deref().map(str::trim) {
+                        Some(term) if !term.is_empty() => term,
+                        _ => bail!("Expected: :search <term>"),
+                    };
+                    let crates = local_registry::search(term);
+                    if crates.is_empty() {
+                        return text_output(format!(
+                            "No crates matching `{}` found in the local registry cache",
+                            term
+                        ));
+                    }
+                    Ok(EvalOutputs::text_html(
+                        cached_crates_as_text(&crates),
+                        cached_crates_as_html(&crates),
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":load_crate",
+                "Add a local crate as a dependency and import it. e.g. :load_crate ../mycrate --deps",
+                process_load_crate_command,
+            )
+            .with_analysis_callback(|_ctx, state, args| {
+                add_local_crate(state, args)?;
+                Ok(EvalOutputs::default())
+            }),
+            AvailableCommand::new(
+                ":patch",
+                "Override a dependency, as per [patch]. e.g. :patch crates-io foo = { path = \"foo\" }. \
+                 Remove with :patch --remove <source> <name>",
+                |_ctx, state, args| {
+                    use regex::Regex;
+                    let args = args.as_deref().unwrap_or_default();
+                    if let Some(rest) = args.strip_prefix("--remove") {
+                        return match rest.split_whitespace().collect::<Vec<_>>().as_slice() {
+                            [source, name] => {
+                                state.remove_patch(source, name)?;
+                                Ok(EvalOutputs::new())
+                            }
+                            _ => bail!("Expected: :patch --remove <source> <name>"),
+                        };
+                    }
+                    static PATCH_RE: OnceCell<Regex> = OnceCell::new();
+                    let patch_re = PATCH_RE
+                        .get_or_init(|| Regex::new("^([^ ]+) +([^= ]+) *= *(.+)$").unwrap());
+                    match patch_re.captures(args) {
+                        Some(captures) => {
+                            state.add_patch(&captures[1], &captures[2], &captures[3])?;
+                            Ok(EvalOutputs::new())
+                        }
+                        None => bail!("Expected: :patch <source> <name> = <dependency>"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":replace",
+                "Replace a package, as per [replace]. e.g. :replace foo:1.0.0 = { path = \"foo\" }. \
+                 Remove with :replace --remove <package>",
+                |_ctx, state, args| {
+                    use regex::Regex;
+                    let args = args.as_deref().unwrap_or_default();
+                    if let Some(rest) = args.strip_prefix("--remove") {
+                        return match rest.trim() {
+                            "" => bail!("Expected: :replace --remove <package>"),
+                            package => {
+                                state.remove_replacement(package.trim_matches('"'))?;
+                                Ok(EvalOutputs::new())
+                            }
+                        };
+                    }
+                    static REPLACE_RE: OnceCell<Regex> = OnceCell::new();
+                    let replace_re = REPLACE_RE
+                        .get_or_init(|| Regex::new("^\"?([^\"= ]+)\"? *= *(.+)$").unwrap());
+                    match replace_re.captures(args) {
+                        Some(captures) => {
+                            state.add_replacement(&captures[1], &captures[2])?;
+                            Ok(EvalOutputs::new())
+                        }
+                        None => bail!("Expected: :replace <package> = <dependency>"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":cargo_config",
+                "Add cargo configuration. e.g. :cargo_config net.git-fetch-with-cli = true. \
+                 Remove with :cargo_config --remove <key>",
+                |ctx, state, args| {
+                    let args = args.as_deref().unwrap_or_default();
+                    if let Some(key) = args.strip_prefix("--remove") {
+                        match key.trim() {
+                            "" => bail!("Expected: :cargo_config --remove <key>"),
+                            key => state.remove_cargo_config(key)?,
+                        }
+                    } else if args.contains('=') {
+                        state.add_cargo_config(args.trim())?;
+                    } else {
+                        bail!("Expected: :cargo_config <key> = <value>");
+                    }
+                    // Write the configuration straight away, so that it's used when validating
+                    // subsequent dependencies.
+                    ctx.eval_context.write_cargo_toml(state)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":proc_macro",
+                "Compile the rest of the cell as a proc-macro crate. e.g. :proc_macro my_derive",
+                |_ctx, _state, _args| {
+                    bail!(":proc_macro must be on the first line of a cell, followed by the source of the crate")
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":link",
+                "Link a native library. e.g. :link foo:static path/to/lib/dir. \
+                 Remove with :link --remove foo",
+                |_ctx, state, args| {
+                    let mut args = args.as_deref().unwrap_or_default().split_whitespace();
+                    let lib = match args.next() {
+                        Some("--remove") => {
+                            match args.next() {
+                                Some(name) => state.remove_native_lib(name)?,
+                                None => bail!("Expected: :link --remove name"),
+                            }
+                            return Ok(EvalOutputs::new());
+                        }
+                        Some(lib) => lib,
+                        None => bail!("Expected: :link name[:kind] [search-path]"),
+                    };
+                    let (name, kind) = match lib.split_once(':') {
+                        Some((name, kind)) => (name, Some(kind)),
+                        None => (lib, None),
+                    };
+                    state.add_native_lib(name, kind, args.next().map(Path::new))?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":cdep",
+                "Compile a C source file and link it. e.g. :cdep foo.c. Remove with :cdep --remove foo.c",
+                |_ctx, state, args| {
+                    let args = args.as_deref().unwrap_or_default();
+                    if let Some(source) = args.strip_prefix("--remove") {
+                        match source.trim() {
+                            "" => bail!("Expected: :cdep --remove <path to C source file>"),
+                            source => state.remove_c_dep(Path::new(source))?,
+                        }
+                        return Ok(EvalOutputs::new());
+                    }
+                    let source = match args.trim() {
+                        "" => bail!("Expected: :cdep <path to C source file>"),
+                        source => source,
+                    };
+                    state.add_c_dep(Path::new(source))?;
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":deps",
+                "List dependencies with the versions and features that cargo selected",
+                |ctx, state, _args| {
+                    ctx.eval_context.write_cargo_toml(state)?;
+                    let deps = state.resolved_dependencies()?;
+                    Ok(EvalOutputs::text_html(
+                        deps_as_text(&deps),
+                        deps_as_html(&deps),
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":features",
+                "List the features of a dependency. e.g. :features regex",
+                |ctx, state, args| {
+                    let name = match args {
+                        Some(name) => name.trim(),
+                        None => bail!(":features requires the name of a dependency"),
+                    };
+                    ctx.eval_context.write_cargo_toml(state)?;
+                    let deps = state.resolved_dependencies()?;
+                    let dep = match deps
+                        .iter()
+                        .find(|dep| dep.name.replace('-', "_") == name.replace('-', "_"))
+                    {
+                        Some(dep) => dep,
+                        None => bail!(
+                            "`{}` isn't a dependency. Add it with :dep first",
+                            name
+                        ),
+                    };
+                    Ok(EvalOutputs::text_html(
+                        features_as_text(dep),
+                        features_as_html(dep),
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":tree",
+                "Print the full dependency tree",
+                |ctx, state, _args| {
+                    ctx.eval_context.write_cargo_toml(state)?;
+                    let tree = state.dependency_tree()?;
+                    let mut html = String::new();
+                    html.push_str("<pre>");
+                    html_escape(&tree, &mut html);
+                    html.push_str("</pre>");
+                    Ok(EvalOutputs::text_html(tree, html))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":lock",
+                "Save or load Cargo.lock to pin dependency versions. e.g. :lock save Cargo.lock",
+                process_lock_command,
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":expand",
+                "Print what the macro calls in some code expand to. e.g. :expand println!(\"{}\", 1). \
+                 On its own on the first line of a cell, expands the rest of the cell",
+                |ctx, state, args| match args.as_deref().map(str::trim) {
+                    Some(code) if !code.is_empty() => ctx.expand_macros(code, state),
+                    _ => bail!("Expected: :expand <code>"),
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":asm",
+                "Print the assembly generated for a function. e.g. :asm foo",
+                |ctx, state, args| ctx.emitted_function(args.as_deref(), EmitKind::Asm, state),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":llvm_ir",
+                "Print the LLVM IR generated for a function. e.g. :llvm_ir foo",
+                |ctx, state, args| ctx.emitted_function(args.as_deref(), EmitKind::LlvmIr, state),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":mir",
+                "Print the MIR generated for a function. e.g. :mir foo",
+                |ctx, state, args| ctx.emitted_function(args.as_deref(), EmitKind::Mir, state),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":type",
+                "Print the type of an expression without running it. e.g. :type vec![1, 2]",
+                |ctx, state, args| {
+                    let expression = match args.as_deref().map(str::trim) {
+                        Some(expression) if !expression.is_empty() => expression,
+                        _ => bail!("Expected: :type <expression>"),
+                    };
+                    match ctx.eval_context.expression_type(expression, state.clone())? {
+                        TypeName::Named(type_name) => text_output(type_name),
+                        TypeName::Closure => text_output("closure"),
+                        TypeName::Unknown => bail!("Couldn't determine the type of `{}`", expression),
+                    }
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":doc",
+                "Print the documentation for an item. e.g. :doc std::vec::Vec",
+                |ctx, state, args| {
+                    let path = match args.as_deref().map(str::trim) {
+                        Some(path) if !path.is_empty() => path,
+                        _ => bail!("Expected: :doc <path>"),
+                    };
+                    match ctx.item_docs(path, state)? {
+                        Some(hover) => {
+                            let mut outputs = text_output(hover.plain_text())?;
+                            outputs
+                                .content_by_mime_type
+                                .insert("text/markdown".to_owned(), hover.markdown);
+                            Ok(outputs)
+                        }
+                        None => bail!("Couldn't find documentation for `{}`", path),
+                    }
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":last_compile_dir",
                 "Print the directory in which we last compiled",
@@ -461,6 +966,16 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Toolchain: {}", state.toolchain()))
                 },
             ),
+            AvailableCommand::new(
+                ":rustflags",
+                "Set/print extra flags to pass to rustc. e.g. :rustflags -C target-cpu=native",
+                |_ctx, state, args| {
+                    if let Some(flags) = args {
+                        state.set_rustflags(flags.split_whitespace().map(str::to_owned).collect());
+                    }
+                    text_output(format!("rustflags: {}", state.rustflags().join(" ")))
+                },
+            ),
             AvailableCommand::new(
                 ":offline",
                 "Set offline mode when invoking cargo",
@@ -469,6 +984,14 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Offline mode: {}", state.offline_mode()))
                 },
             ),
+            AvailableCommand::new(
+                ":auto_dep",
+                "Automatically add crates from the local registry cache when used by code (0/1)",
+                |_ctx, state, args| {
+                    state.set_auto_dep(args.as_ref().map(String::as_str) == Some("1"));
+                    text_output(format!("Auto dependencies: {}", state.auto_dep()))
+                },
+            ),
             AvailableCommand::new(
                 ":quit",
                 "Quit evaluation and exit",
@@ -588,6 +1111,251 @@ Panic detected. Here's some useful information if you're filing a bug report.
     }
 }
 
+/// If `code` is a `:proc_macro` cell, returns the name of the crate to define and its source. A
+/// `:proc_macro` cell starts with a `:proc_macro name` line and the rest of the cell is the source
+/// of the crate.
+fn parse_proc_macro_cell(code: &str) -> Option<(&str, &str)> {
+    use regex::Regex;
+    static PROC_MACRO_RE: OnceCell<Regex> = OnceCell::new();
+    let proc_macro_re = PROC_MACRO_RE.get_or_init(|| {
+        Regex::new(r"^[ \t\r\n]*:proc_macro +([A-Za-z_][A-Za-z0-9_]*) *\r?\n").unwrap()
+    });
+    let captures = proc_macro_re.captures(code)?;
+    let header = captures.get(0)?;
+    Some((captures.get(1)?.as_str(), &code[header.end()..]))
+}
+
+/// If `code` is an `:expand` cell, returns the code to expand, which is everything after the first
+/// line.
+fn parse_expand_cell(code: &str) -> Option<&str> {
+    use regex::Regex;
+    static EXPAND_RE: OnceCell<Regex> = OnceCell::new();
+    let expand_re = EXPAND_RE.get_or_init(|| Regex::new(r"^[ \t\r\n]*:expand *\r?\n").unwrap());
+    let header = expand_re.find(code)?;
+    Some(&code[header.end()..])
+}
+
+/// Returns the byte offset within `code` of the name of each macro call in `code`.
+fn macro_call_offsets(code: &str) -> Vec<usize> {
+    use ra_ap_syntax::ast;
+    use ra_ap_syntax::AstNode;
+    // Parse the code as the body of a function, since that's how we'd run it.
+    const PRELUDE: &str = "fn f(){";
+    let parsed = ra_ap_syntax::SourceFile::parse(&format!("{}{}}}", PRELUDE, code));
+    parsed
+        .syntax_node()
+        .descendants()
+        .filter_map(ast::MacroCall::cast)
+        .filter_map(|call| {
+            let name = call.path()?.segment()?.name_ref()?;
+            usize::from(name.syntax().text_range().start()).checked_sub(PRELUDE.len())
+        })
+        .collect()
+}
+
+fn process_lock_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or_default();
+    let (action, path) = match args.split_once(' ') {
+        Some((action, path)) if !path.trim().is_empty() => (action, path.trim()),
+        _ => bail!("Expected: :lock save <path> or :lock load <path>"),
+    };
+    match action {
+        "save" => {
+            ctx.eval_context.write_cargo_toml(state)?;
+            let contents = ctx.eval_context.lockfile_contents(state)?;
+            if let Err(err) = std::fs::write(path, contents) {
+                bail!("Error writing '{}': {}", path, err);
+            }
+            text_output(format!("Saved lockfile to {}", path))
+        }
+        "load" => {
+            let contents = match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) => bail!("Error reading '{}': {}", path, err),
+            };
+            ctx.eval_context.load_lockfile(contents, state)?;
+            text_output(format!("Loaded lockfile from {}", path))
+        }
+        _ => bail!("Unknown :lock action `{}`. Expected save or load", action),
+    }
+}
+
+fn process_load_crate_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let (local_crate, with_deps) = add_local_crate(state, args)?;
+    let mut outputs = EvalOutputs::new();
+    if let Some(lib_name) = &local_crate.lib_name {
+        outputs = ctx
+            .eval_context
+            .eval_with_state(&format!("use {};", lib_name), state.clone())?;
+        *state = ctx.eval_context.state();
+    }
+    let mut message = format!("Loaded crate {} from {}", local_crate.name, local_crate.dir);
+    if local_crate.lib_name.is_none() {
+        message.push_str("\nThe crate has no library, so there's nothing to import");
+    }
+    if with_deps && !local_crate.skipped_dependencies.is_empty() {
+        message.push_str(&format!(
+            "\nSkipped dependencies: {}",
+            local_crate.skipped_dependencies.join(", ")
+        ));
+    }
+    outputs.merge(text_output(message)?);
+    Ok(outputs)
+}
+
+/// Parses the arguments to `:load_crate` and adds the specified crate and, if `--deps` was given,
+/// its dependencies to `state`. Returns the crate and whether its dependencies were added.
+fn add_local_crate(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<(LocalCrate, bool), Error> {
+    let (path, with_deps) = parse_load_crate_args(args.as_deref().unwrap_or_default());
+    let local_crate = state.local_crate(Path::new(path))?;
+    state.add_dep(
+        &local_crate.name,
+        &format!("{{ path = \"{}\" }}", local_crate.dir.replace('\\', "\\\\")),
+    )?;
+    if with_deps {
+        for (name, config) in &local_crate.dependencies {
+            state.add_dep(name, config)?;
+        }
+    }
+    Ok((local_crate, with_deps))
+}
+
+/// Splits the arguments to `:load_crate` into the crate's path and whether `--deps` was given. The
+/// flag may come before or after the path. Everything else is the path, which may contain spaces
+/// and may optionally be quoted.
+fn parse_load_crate_args(args: &str) -> (&str, bool) {
+    let mut path = args.trim();
+    let mut with_deps = false;
+    if let Some(rest) = path.strip_prefix("--deps") {
+        if rest.is_empty() || rest.starts_with(' ') {
+            path = rest.trim_start();
+            with_deps = true;
+        }
+    }
+    if let Some(rest) = path.strip_suffix("--deps") {
+        if rest.is_empty() || rest.ends_with(' ') {
+            path = rest.trim_end();
+            with_deps = true;
+        }
+    }
+    if path.len() >= 2 && path.starts_with('"') && path.ends_with('"') {
+        path = &path[1..path.len() - 1];
+    }
+    if path.is_empty() {
+        path = ".";
+    }
+    (path, with_deps)
+}
+
+fn deps_as_text(deps: &[ResolvedDependency]) -> String {
+    let mut out = String::new();
+    for dep in deps {
+        out.push_str(&format!("{} = {} ({})", dep.name, dep.version, dep.source));
+        if !dep.features.is_empty() {
+            out.push_str(&format!(" features: {}", dep.features.join(", ")));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn deps_as_html(deps: &[ResolvedDependency]) -> String {
+    let mut out = String::new();
+    out.push_str("<table><tr><th>Crate</th><th>Version</th><th>Features</th><th>Source</th></tr>");
+    for dep in deps {
+        out.push_str("<tr><td>");
+        html_escape(&dep.name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&dep.version, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&dep.features.join(", "), &mut out);
+        out.push_str("</td><td>");
+        html_escape(&dep.source, &mut out);
+        out.push_str("</td></tr>");
+    }
+    out.push_str("</table>");
+    out
+}
+
+/// Returns notes to display next to `feature`, e.g. whether it's enabled.
+fn feature_notes(dep: &ResolvedDependency, feature: &str) -> Vec<&'static str> {
+    let mut notes = Vec::new();
+    if dep.default_features.iter().any(|f| f == feature) {
+        notes.push("default");
+    }
+    if dep.features.iter().any(|f| f == feature) {
+        notes.push("enabled");
+    }
+    notes
+}
+
+fn features_as_text(dep: &ResolvedDependency) -> String {
+    let mut out = String::new();
+    for feature in &dep.available_features {
+        out.push_str(feature);
+        let notes = feature_notes(dep, feature);
+        if !notes.is_empty() {
+            out.push_str(&format!(" ({})", notes.join(", ")));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn features_as_html(dep: &ResolvedDependency) -> String {
+    let mut out = String::new();
+    out.push_str("<table><tr><th>Feature</th><th>Default</th><th>Enabled</th></tr>");
+    for feature in &dep.available_features {
+        let notes = feature_notes(dep, feature);
+        out.push_str("<tr><td>");
+        html_escape(feature, &mut out);
+        out.push_str("</td><td>");
+        if notes.contains(&"default") {
+            out.push_str("&#x2713;");
+        }
+        out.push_str("</td><td>");
+        if notes.contains(&"enabled") {
+            out.push_str("&#x2713;");
+        }
+        out.push_str("</td></tr>");
+    }
+    out.push_str("</table>");
+    out
+}
+
+fn cached_crates_as_text(crates: &[CachedCrate]) -> String {
+    let mut out = String::new();
+    for krate in crates {
+        out.push_str(&format!("{}: {}\n", krate.name, krate.versions.join(", ")));
+    }
+    out
+}
+
+fn cached_crates_as_html(crates: &[CachedCrate]) -> String {
+    let mut out = String::new();
+    out.push_str("<table><tr><th>Crate</th><th>Cached versions</th></tr>");
+    for krate in crates {
+        out.push_str("<tr><td>");
+        html_escape(&krate.name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&krate.versions.join(", "), &mut out);
+        out.push_str("</td></tr>");
+    }
+    out.push_str("</table>");
+    out
+}
+
 fn process_dep_command(
     state: &mut ContextState,
     args: &Option<String>,
@@ -601,10 +1369,25 @@ fn process_dep_command(
     static DEP_RE: OnceCell<Regex> = OnceCell::new();
     let dep_re = DEP_RE.get_or_init(|| Regex::new("^([^= ]+) *(= *(.+))?$").unwrap());
     if let Some(captures) = dep_re.captures(args) {
-        state.add_dep(
-            &captures[1],
-            captures.get(3).map_or("\"*\"", |m| m.as_str()),
-        )?;
+        if let Some(dep_config) = captures.get(3) {
+            state.add_dep(&captures[1], dep_config.as_str())?;
+            return Ok(EvalOutputs::new());
+        }
+        // When offline, cargo can only use crates that it has already downloaded, so we pick the
+        // newest of those rather than leaving it up to cargo.
+        if state.offline_mode() {
+            if let Some(krate) = local_registry::find(&captures[1]) {
+                if let Some(dep_config) = krate.dep_config(&captures[1]) {
+                    state.add_dep(&captures[1], &dep_config)?;
+                    return text_output(format!(
+                        "Using cached version {} of {}",
+                        krate.newest_version().unwrap_or_default(),
+                        krate.name
+                    ));
+                }
+            }
+        }
+        state.add_dep(&captures[1], "\"*\"")?;
         Ok(EvalOutputs::new())
     } else {
         bail!("Invalid :dep command. Expected: name = ... or just name");
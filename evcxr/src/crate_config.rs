@@ -28,15 +28,23 @@ pub(crate) struct ExternalCrate {
     pub(crate) config: String,
 }
 
+fn path_regex() -> &'static Regex {
+    static PATH_RE: OnceCell<Regex> = OnceCell::new();
+    PATH_RE.get_or_init(|| Regex::new("^(.*)path *= *\"([^\"]+)\"(.*)$").unwrap())
+}
+
+/// Returns whether `config`, the config of a dependency, refers to a local path.
+pub(crate) fn is_path_dep(config: &str) -> bool {
+    path_regex().is_match(config)
+}
+
 fn make_paths_absolute(config: String) -> Result<String, Error> {
     // Perhaps not the nicest way to do this. Using a toml parser would possibly
     // be nicer. At the time this was written that wasn't an option due to a
     // compiler bug that prevented us from using any crate that used custom
     // derive. That bug is long fixed though, so switching this to use a toml
     // parser would be an option.
-    static PATH_RE: OnceCell<Regex> = OnceCell::new();
-    let path_re = PATH_RE.get_or_init(|| Regex::new("^(.*)path *= *\"([^\"]+)\"(.*)$").unwrap());
-    if let Some(captures) = path_re.captures(&config) {
+    if let Some(captures) = path_regex().captures(&config) {
         let path = Path::new(&captures[2]);
         if !path.is_absolute() {
             match path.canonicalize() {
@@ -61,6 +69,14 @@ impl ExternalCrate {
         let config = make_paths_absolute(config)?;
         Ok(ExternalCrate { name, config })
     }
+
+    /// Returns the directory containing the crate if it's a path dependency.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        path_regex()
+            .captures(&self.config)
+            .and_then(|captures| captures.get(2))
+            .map(|m| Path::new(m.as_str()))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,230 @@
+// Copyright 2026 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Something other than a compiled library that we can ask rustc to write out for our crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmitKind {
+    Asm,
+    LlvmIr,
+    Mir,
+}
+
+impl EmitKind {
+    /// The value to pass to rustc's `--emit` option.
+    pub(crate) fn rustc_emit_type(self) -> &'static str {
+        match self {
+            EmitKind::Asm => "asm",
+            EmitKind::LlvmIr => "llvm-ir",
+            EmitKind::Mir => "mir",
+        }
+    }
+
+    /// The extension of the file that rustc writes.
+    pub(crate) fn file_extension(self) -> &'static str {
+        match self {
+            EmitKind::Asm => "s",
+            EmitKind::LlvmIr => "ll",
+            EmitKind::Mir => "mir",
+        }
+    }
+}
+
+/// Returns the code of each function in `output` that's named `name`, with any symbols demangled.
+/// `name` may be qualified with some or all of its path, e.g. `Foo::bar`. There may be more than
+/// one function, e.g. if a generic function was instantiated with different types.
+pub(crate) fn extract_functions(kind: EmitKind, output: &str, name: &str) -> Vec<String> {
+    let mut functions = Vec::new();
+    let mut lines = output.lines();
+    while let Some(line) = lines.next() {
+        match function_name(kind, line) {
+            Some(function) if name_matches(&function, name) => {}
+            _ => continue,
+        }
+        let mut function = demangle_symbols(kind, line);
+        for line in lines.by_ref() {
+            if kind == EmitKind::Asm {
+                if is_end_of_asm_function(line) {
+                    break;
+                }
+                // Call frame and debug information are noise as far as someone reading the code
+                // is concerned.
+                let line = line.trim_start();
+                if [".cfi_", ".loc\t", ".file\t"]
+                    .iter()
+                    .any(|directive| line.starts_with(directive))
+                {
+                    continue;
+                }
+            }
+            function.push('\n');
+            function.push_str(&demangle_symbols(kind, line));
+            if kind != EmitKind::Asm && line == "}" {
+                break;
+            }
+        }
+        functions.push(function);
+    }
+    functions
+}
+
+/// If `line` starts the definition of a function, returns the function's demangled name.
+fn function_name(kind: EmitKind, line: &str) -> Option<String> {
+    let symbol = match kind {
+        EmitKind::Asm => {
+            if line.starts_with(char::is_whitespace) {
+                return None;
+            }
+            line.strip_suffix(':')?
+        }
+        EmitKind::LlvmIr => {
+            let rest = line.strip_prefix("define ")?;
+            let rest = &rest[rest.find('@')? + 1..];
+            &rest[..rest.find('(')?]
+        }
+        EmitKind::Mir => {
+            let rest = line.strip_prefix("fn ")?;
+            return Some(rest[..rest.find('(')?].to_owned());
+        }
+    };
+    let symbol = symbol.trim_matches('"');
+    Some(match rustc_demangle::try_demangle(symbol) {
+        Ok(demangled) => format!("{:#}", demangled),
+        Err(_) => symbol.to_owned(),
+    })
+}
+
+/// Returns whether the function `function` could be what the user meant by `name`. Angle brackets
+/// are ignored, so that e.g. `<ctx::Foo>::bar` matches `Foo::bar`.
+fn name_matches(function: &str, name: &str) -> bool {
+    // MIR names methods after their impl block, e.g. `<impl at src/lib.rs:4:1: 4:9>::bar`, rather
+    // than after their type, so for those we can only compare the method name.
+    let name = if function.starts_with("<impl at ") {
+        name.rsplit("::").next().unwrap_or(name)
+    } else {
+        name
+    };
+    let function = function.replace(['<', '>'], "");
+    function == name || function.ends_with(&format!("::{}", name))
+}
+
+fn is_end_of_asm_function(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with(".cfi_endproc")
+        || line.starts_with(".seh_endproc")
+        || line.starts_with(".Lfunc_end")
+        || line.starts_with("Lfunc_end")
+}
+
+/// Replaces any mangled symbol names in `line` with their demangled equivalents. MIR doesn't
+/// contain mangled names, so is left as-is.
+fn demangle_symbols(kind: EmitKind, line: &str) -> String {
+    if kind == EmitKind::Mir {
+        return line.to_owned();
+    }
+    let is_symbol_char = |ch: char| ch.is_ascii_alphanumeric() || "_$.".contains(ch);
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|ch: char| !is_symbol_char(ch))
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(end);
+        match rustc_demangle::try_demangle(word) {
+            Ok(demangled) => out.push_str(&format!("{:#}", demangled)),
+            Err(_) => out.push_str(word),
+        }
+        // Copy across everything up to the start of the next possible symbol.
+        let next = after.find(is_symbol_char).unwrap_or(after.len());
+        out.push_str(&after[..next]);
+        rest = &after[next..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_functions;
+    use super::EmitKind;
+
+    #[test]
+    fn asm_function() {
+        let asm = r#"
+	.section	.text._ZN3ctx3foo17h0123456789abcdefE,"ax",@progbits
+	.p2align	4, 0x90
+	.type	_ZN3ctx3foo17h0123456789abcdefE,@function
+_ZN3ctx3foo17h0123456789abcdefE:
+	.cfi_startproc
+	leal	(%rdi,%rdi,2), %eax
+	retq
+.Lfunc_end0:
+	.size	_ZN3ctx3foo17h0123456789abcdefE, .Lfunc_end0-_ZN3ctx3foo17h0123456789abcdefE
+	.cfi_endproc
+
+_ZN3ctx7foo_bar17h0123456789abcdefE:
+	.cfi_startproc
+	callq	*_ZN3ctx3foo17h0123456789abcdefE@GOTPCREL(%rip)
+	retq
+.Lfunc_end1:
+"#;
+        assert_eq!(
+            extract_functions(EmitKind::Asm, asm, "foo"),
+            vec!["ctx::foo:\n\tleal\t(%rdi,%rdi,2), %eax\n\tretq".to_owned()]
+        );
+        assert_eq!(
+            extract_functions(EmitKind::Asm, asm, "foo_bar"),
+            vec!["ctx::foo_bar:\n\tcallq\t*ctx::foo@GOTPCREL(%rip)\n\tretq".to_owned()]
+        );
+        assert!(extract_functions(EmitKind::Asm, asm, "bar").is_empty());
+    }
+
+    #[test]
+    fn llvm_ir_function() {
+        let ir = r#"
+; ctx::Foo::get
+define i32 @_ZN3ctx3Foo3get17h0123456789abcdefE(ptr %self) unnamed_addr #0 {
+start:
+  %0 = load i32, ptr %self, align 4
+  ret i32 %0
+}
+
+define i32 @other() unnamed_addr #0 {
+  ret i32 0
+}
+"#;
+        assert_eq!(
+            extract_functions(EmitKind::LlvmIr, ir, "Foo::get"),
+            vec![
+                "define i32 @ctx::Foo::get(ptr %self) unnamed_addr #0 {\nstart:\n  \
+                 %0 = load i32, ptr %self, align 4\n  ret i32 %0\n}"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn mir_function() {
+        let foo = "fn foo(_1: u32) -> u32 {\n    let mut _0: u32;\n\n    bb0: {\n        \
+                   _0 = Mul(_1, const 3_u32);\n        return;\n    }\n}";
+        let get = "fn <impl at src/lib.rs:4:1: 4:9>::get(_1: &Foo) -> u32 {\n}";
+        let mir = format!("{}\n\nfn bar() -> () {{\n}}\n\n{}\n", foo, get);
+        assert_eq!(
+            extract_functions(EmitKind::Mir, &mir, "foo"),
+            vec![foo.to_owned()]
+        );
+        assert_eq!(
+            extract_functions(EmitKind::Mir, &mir, "Foo::get"),
+            vec![get.to_owned()]
+        );
+    }
+}
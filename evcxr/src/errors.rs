@@ -18,6 +18,7 @@ use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
+use crate::rust_analyzer::Diagnostic;
 use ariadne::Color;
 use ariadne::{ColorGenerator, Label, Report, ReportKind};
 use json::JsonValue;
@@ -241,6 +242,43 @@ impl CompilationError {
         }
     }
 
+    /// Returns an error for a problem that rust-analyzer found in `source`, which is the code of
+    /// `code_block`. Returns None if the problem isn't in code that the user supplied.
+    pub(crate) fn from_analyzer_diagnostic(
+        diagnostic: &Diagnostic,
+        source: &str,
+        code_block: &CodeBlock,
+    ) -> Option<CompilationError> {
+        // We express the diagnostic in the same form as rustc's JSON output, so that it gets mapped
+        // back to the user's code in the same way.
+        let (line_start, column_start) = source_line_and_column(source, diagnostic.start_offset);
+        let (line_end, column_end) = source_line_and_column(source, diagnostic.end_offset);
+        let json = json::object! {
+            "message" => diagnostic.message.clone(),
+            "level" => diagnostic.level,
+            "code" => json::object! {
+                "code" => diagnostic.code.clone(),
+            },
+            "spans" => json::array![
+                json::object! {
+                    "file_name" => "src/lib.rs",
+                    "line_start" => line_start,
+                    "line_end" => line_end,
+                    "column_start" => column_start,
+                    "column_end" => column_end,
+                    "is_primary" => true,
+                }
+            ],
+            "children" => json::array![],
+        };
+        let error = CompilationError::opt_new(json, code_block)?;
+        if error.is_from_user_code() {
+            Some(error)
+        } else {
+            None
+        }
+    }
+
     /// Returns a synthesized error that spans the specified portion of `segment`.
     pub(crate) fn from_segment_span(
         segment: &Segment,
@@ -463,6 +501,16 @@ fn line_and_column(
     (start_line + line - 1, column)
 }
 
+/// Returns the 1-based line and column (character) of the byte offset `position` within `source`.
+fn source_line_and_column(source: &str, position: usize) -> (usize, usize) {
+    let before = &source[..position.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        count_columns(&before[line_start..]) + 1,
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct SpannedMessage {
     pub span: Option<Span>,
@@ -562,6 +610,8 @@ pub enum Error {
     TypeRedefinedVariablesLost(Vec<String>),
     Message(String),
     SubprocessTerminated(String),
+    /// The operation was interrupted, e.g. a build was cancelled at the user's request.
+    Interrupted,
 }
 
 impl std::error::Error for Error {}
@@ -584,6 +634,7 @@ impl fmt::Display for Error {
             Error::Message(message) | Error::SubprocessTerminated(message) => {
                 write!(f, "{}", message)?
             }
+            Error::Interrupted => write!(f, "Interrupted")?,
         }
         Ok(())
     }
@@ -12,12 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cargo_metadata::LocalCrate;
+use crate::cargo_metadata::ResolvedDependency;
 use crate::child_process::ChildProcess;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
 use crate::crate_config::ExternalCrate;
+use crate::emitted_code::EmitKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
@@ -25,11 +28,19 @@ use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::evcxr_internal_runtime;
 use crate::item;
+use crate::module::BackgroundBuildOutcome;
+use crate::module::BuildCancellationHandle;
 use crate::module::Module;
 use crate::module::SoFile;
+use crate::module::StableHasher;
 use crate::runtime;
+use crate::rust_analyzer::AnalysisFile;
 use crate::rust_analyzer::Completions;
+use crate::rust_analyzer::Hover;
+use crate::rust_analyzer::MacroExpansion;
+use crate::rust_analyzer::Position;
 use crate::rust_analyzer::RustAnalyzer;
+use crate::rust_analyzer::SignatureHelp;
 use crate::rust_analyzer::TypeName;
 use crate::rust_analyzer::VariableInfo;
 use crate::use_trees::Import;
@@ -41,6 +52,7 @@ use ra_ap_syntax::AstNode;
 use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::Path;
@@ -60,11 +72,21 @@ pub struct EvalContext {
     stdout_sender: crossbeam_channel::Sender<String>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    /// Use statements needed by completions that we've offered since the last eval, keyed by the
+    /// name that they import. There can be several for a name, e.g. `std::cmp::Ordering` and
+    /// `std::sync::atomic::Ordering`. If the next eval fails because a name can't be resolved and
+    /// there's exactly one candidate for it, we add the import.
+    pending_imports: HashMap<String, Vec<String>>,
+    /// Our dependencies from before we started building new ones in the background. These are
+    /// restored if the background build fails.
+    deps_before_background_build: Option<HashMap<String, ExternalCrate>>,
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     pub(crate) crate_dir: PathBuf,
+    /// The root of the Cargo workspace that contains `crate_dir`.
+    pub(crate) workspace_dir: PathBuf,
     pub(crate) debug_mode: bool,
     // Whether we should preserve variables that are Copy when a panic occurs.
     // Sounds good, but unfortunately doing so currently requires an extra build
@@ -88,13 +110,36 @@ pub(crate) struct Config {
     pub(crate) sccache: Option<PathBuf>,
     /// Whether to attempt to avoid network access.
     pub(crate) offline_mode: bool,
+    /// Whether to automatically add dependencies on crates in the local registry cache when code
+    /// refers to them.
+    pub(crate) auto_dep: bool,
     pub(crate) toolchain: String,
+    /// The contents of a Cargo.lock to be used for the workspace, as loaded by `:lock load`.
+    pub(crate) pinned_lockfile: Option<String>,
+    /// Entries for `[patch.<source>]` tables, keyed by source, then by crate name.
+    pub(crate) patches: BTreeMap<String, BTreeMap<String, String>>,
+    /// Entries for the `[replace]` table, keyed by package ID spec.
+    pub(crate) replacements: BTreeMap<String, String>,
+    /// Lines to be written to `.cargo/config.toml` in the workspace directory. e.g. for source
+    /// replacement.
+    pub(crate) cargo_config: Vec<String>,
+    /// The names of crates defined by `:proc_macro` cells.
+    pub(crate) proc_macro_crates: Vec<String>,
+    /// Native libraries to link against, as added by `:link`. Each is passed to rustc's `-l`.
+    pub(crate) native_libs: Vec<String>,
+    /// Directories in which to search for native libraries, as added by `:link`.
+    pub(crate) native_lib_search_paths: Vec<PathBuf>,
+    /// Static libraries compiled from C source files by `:cdep`, keyed by source file.
+    pub(crate) c_deps: BTreeMap<PathBuf, String>,
+    /// Extra flags to pass to rustc when compiling our crate, as set by `:rustflags`.
+    pub(crate) rustflags: Vec<String>,
     cargo_path: String,
     pub(crate) rustc_path: String,
 }
 
-fn create_initial_config(crate_dir: PathBuf) -> Config {
+fn create_initial_config(crate_dir: PathBuf, workspace_dir: PathBuf) -> Config {
     let mut config = Config::new(crate_dir);
+    config.workspace_dir = workspace_dir;
     // default the linker to mold, then lld, first checking if either are installed
     // neither linkers support macos, so fallback to system (aka default)
     // https://github.com/rui314/mold/issues/132
@@ -109,6 +154,7 @@ fn create_initial_config(crate_dir: PathBuf) -> Config {
 impl Config {
     pub fn new(crate_dir: PathBuf) -> Self {
         Config {
+            workspace_dir: crate_dir.clone(),
             crate_dir,
             debug_mode: false,
             preserve_vars_on_panic: true,
@@ -121,7 +167,17 @@ impl Config {
             linker: "system".to_owned(),
             sccache: None,
             offline_mode: false,
+            auto_dep: false,
             toolchain: String::new(),
+            pinned_lockfile: None,
+            patches: BTreeMap::new(),
+            replacements: BTreeMap::new(),
+            cargo_config: Vec::new(),
+            proc_macro_crates: Vec::new(),
+            native_libs: Vec::new(),
+            native_lib_search_paths: Vec::new(),
+            c_deps: BTreeMap::new(),
+            rustflags: Vec::new(),
             cargo_path: default_cargo_path(),
             rustc_path: default_rustc_path(),
         }
@@ -144,6 +200,45 @@ impl Config {
         self.sccache.is_some()
     }
 
+    /// Returns the `[patch]` and `[replace]` tables to be included in a workspace's Cargo.toml.
+    pub(crate) fn patch_tables(&self) -> String {
+        let mut out = String::new();
+        for (source, patches) in &self.patches {
+            if source
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                out.push_str(&format!("\n[patch.{}]\n", source));
+            } else {
+                out.push_str(&format!("\n[patch.\"{}\"]\n", source));
+            }
+            for (name, spec) in patches {
+                out.push_str(&format!("{} = {}\n", name, spec));
+            }
+        }
+        if !self.replacements.is_empty() {
+            out.push_str("\n[replace]\n");
+            for (package, spec) in &self.replacements {
+                out.push_str(&format!("\"{}\" = {}\n", package, spec));
+            }
+        }
+        out
+    }
+
+    pub(crate) fn cargo_config_contents(&self) -> String {
+        let mut out = String::new();
+        for line in &self.cargo_config {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The directory into which `:cdep` writes the static libraries that it compiles.
+    pub(crate) fn c_deps_dir(&self) -> PathBuf {
+        self.workspace_dir.join("cdeps")
+    }
+
     pub(crate) fn cargo_command(&self, command_name: &str) -> Command {
         let mut command = if self.linker == "mold" {
             Command::new("mold")
@@ -163,6 +258,17 @@ impl Config {
         command.current_dir(&self.crate_dir);
         command
     }
+
+    /// Returns a command that runs the rustc that cargo builds our code with.
+    pub(crate) fn rustc_command(&self) -> Command {
+        let mut command = Command::new(&self.rustc_path);
+        if !self.toolchain.is_empty() {
+            // This is what rustup sets when we run `cargo +toolchain`, so if `rustc_path` is a
+            // rustup proxy, it picks the same toolchain as cargo does.
+            command.env("RUSTUP_TOOLCHAIN", &self.toolchain);
+        }
+        command
+    }
 }
 
 #[derive(Debug)]
@@ -221,18 +327,52 @@ pub struct InputRequest {
     pub is_password: bool,
 }
 
+/// Progress of a build. Reported each time cargo finishes building a crate that wasn't already
+/// up-to-date.
+#[derive(Clone, Debug)]
+pub struct BuildProgress {
+    /// The number of crates that have been built (or found to be up-to-date) so far, including
+    /// the one that was just built.
+    pub crates_built: usize,
+    /// The number of crates that we expect to build, including the crate containing the user's
+    /// code.
+    pub total_crates: usize,
+    /// The name of the crate that was just built.
+    pub last_crate: String,
+}
+
+/// Callbacks that are invoked while evaluating code. Construct with `EvalCallbacks::default()`,
+/// then set whichever callbacks are needed, e.g. with `with_build_progress`.
 pub struct EvalCallbacks<'a> {
     pub input_reader: &'a dyn Fn(InputRequest) -> String,
+    pub build_progress: &'a dyn Fn(BuildProgress),
+}
+
+impl<'a> EvalCallbacks<'a> {
+    /// Sets the callback that's used to read input when the user's code requests it.
+    pub fn with_input_reader(mut self, input_reader: &'a dyn Fn(InputRequest) -> String) -> Self {
+        self.input_reader = input_reader;
+        self
+    }
+
+    /// Sets the callback that's invoked each time cargo finishes building a crate.
+    pub fn with_build_progress(mut self, build_progress: &'a dyn Fn(BuildProgress)) -> Self {
+        self.build_progress = build_progress;
+        self
+    }
 }
 
 fn default_input_reader(_: InputRequest) -> String {
     String::new()
 }
 
+fn default_build_progress(_: BuildProgress) {}
+
 impl<'a> Default for EvalCallbacks<'a> {
     fn default() -> Self {
         EvalCallbacks {
             input_reader: &default_input_reader,
+            build_progress: &default_build_progress,
         }
     }
 }
@@ -277,9 +417,13 @@ impl EvalContext {
             .parent()
             .unwrap()
             .join("testing_runtime");
-        let (mut context, outputs) =
-            EvalContext::with_subprocess_command(std::process::Command::new(&testing_runtime_path))
-                .unwrap();
+        // Tests share a cache, but don't use the user's, so as not to fill it up.
+        let cache_dir = testing_runtime_path.with_file_name("evcxr_test_cache");
+        let (mut context, outputs) = EvalContext::with_subprocess_command_and_cache_dir(
+            std::process::Command::new(&testing_runtime_path),
+            Some(cache_dir),
+        )
+        .unwrap();
         let mut state = context.state();
         state.set_offline_mode(true);
         context.commit_state(state);
@@ -287,7 +431,19 @@ impl EvalContext {
     }
 
     pub fn with_subprocess_command(
+        subprocess_command: std::process::Command,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_subprocess_command_and_cache_dir(
+            subprocess_command,
+            crate::cache_dir().map(|dir| dir.join("compiled")),
+        )
+    }
+
+    /// Like `with_subprocess_command`, but caches compiled code in `cache_dir`, or if that's None,
+    /// in our temporary directory, so that it's only reused within this session.
+    fn with_subprocess_command_and_cache_dir(
         mut subprocess_command: std::process::Command,
+        cache_dir: Option<PathBuf>,
     ) -> Result<(EvalContext, EvalContextOutputs), Error> {
         let mut opt_tmpdir = None;
         let tmpdir_path;
@@ -299,15 +455,19 @@ impl EvalContext {
             opt_tmpdir = Some(tmpdir);
         }
 
-        let analyzer = RustAnalyzer::new(&tmpdir_path)?;
-        let module = Module::new(tmpdir_path)?;
+        let module = Module::new(tmpdir_path, cache_dir)?;
+        let analyzer = RustAnalyzer::new(
+            &module.crate_dir(),
+            Some(Path::new(subprocess_command.get_program())),
+        )?;
 
         Self::apply_platform_specific_vars(&module, &mut subprocess_command);
 
         let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
         let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
         let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
-        let initial_config = create_initial_config(module.crate_dir().to_owned());
+        let initial_config =
+            create_initial_config(module.crate_dir(), module.workspace_dir().to_owned());
         let initial_state = ContextState::new(initial_config.clone());
         let mut context = EvalContext {
             _tmpdir: opt_tmpdir,
@@ -317,6 +477,8 @@ impl EvalContext {
             stdout_sender,
             analyzer,
             initial_config,
+            pending_imports: HashMap::new(),
+            deps_before_background_build: None,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
@@ -358,7 +520,13 @@ impl EvalContext {
         state: ContextState,
     ) -> Result<EvalOutputs, Error> {
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
-        self.eval_with_callbacks(user_code, state, &code_info, &mut EvalCallbacks::default())
+        let cancellation = self.build_cancellation_handle();
+        cancellation.start_building();
+        let result =
+            self.eval_with_callbacks(user_code, state, &code_info, &mut EvalCallbacks::default());
+        // If we got as far as running the code, we'll already have stopped.
+        let _ = cancellation.stop_building();
+        result
     }
 
     pub(crate) fn check(
@@ -375,22 +543,91 @@ impl EvalContext {
         Ok(state.apply_custom_errors(errors, &user_code, code_info))
     }
 
+    /// Like `check`, but only reports problems that rust-analyzer can find by itself. This is much
+    /// faster than running cargo check, but finds fewer problems.
+    pub(crate) fn analyzer_check(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        code_info: &UserCodeInfo,
+    ) -> Result<Vec<CompilationError>, Error> {
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let user_code = state.apply(user_code, &code_info.nodes)?;
+        let code = state.analysis_files(user_code.clone());
+        self.set_analysis_source(&code, &state)?;
+        let mut errors = Vec::new();
+        for file in [AnalysisFile::Lib, AnalysisFile::Cell] {
+            let block = code.block(file);
+            let source = block.code_string();
+            errors.extend(
+                self.analyzer
+                    .diagnostics(file)?
+                    .iter()
+                    .filter_map(|diagnostic| {
+                        CompilationError::from_analyzer_diagnostic(diagnostic, &source, block)
+                    }),
+            );
+        }
+        Ok(state.apply_custom_errors(errors, &user_code, code_info))
+    }
+
     /// Evaluates the supplied Rust code.
     pub(crate) fn eval_with_callbacks(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        let result =
+            self.eval_with_callbacks_and_pending_imports(user_code, state, code_info, callbacks);
+        // Imports from completions are only offered for the code that immediately follows them,
+        // whether or not that code succeeded.
+        self.pending_imports.clear();
+        result
+    }
+
+    fn eval_with_callbacks_and_pending_imports(
         &mut self,
         user_code: CodeBlock,
         mut state: ContextState,
         code_info: &UserCodeInfo,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
-        if user_code.is_empty()
-            && !self
+        let needs_build = !user_code.is_empty()
+            || self
                 .committed_state
-                .state_change_can_fail_compilation(&state)
-        {
+                .state_change_can_fail_compilation(&state);
+        self.report_background_build(needs_build)?;
+        if !needs_build {
             self.commit_state(state);
             return Ok(EvalOutputs::default());
         }
+        if user_code.is_empty() && self.committed_state.only_dependencies_changed(&state) {
+            // The dependencies have already been validated when they were added, so we can commit
+            // the new state straight away and build the dependencies in the background while the
+            // user writes their next bit of code. If the build fails, the dependencies are removed
+            // again when we next evaluate something.
+            self.deps_before_background_build = Some(self.committed_state.external_deps.clone());
+            self.commit_state(state);
+            self.module.write_cargo_toml(&self.committed_state)?;
+            // Errors sending output are ignored, since it just means the user of the library has
+            // dropped the Receiver.
+            let _ = self
+                .stdout_sender
+                .send("Building dependencies in the background...".to_owned());
+            let stdout_sender = self.stdout_sender.clone();
+            self.module
+                .start_background_build(&self.committed_state.config, move |progress| {
+                    let _ = stdout_sender.send(format!(
+                        "Building dependencies [{}/{}]: {}",
+                        progress.crates_built, progress.total_crates, progress.last_crate
+                    ));
+                })?;
+            return Ok(EvalOutputs::default());
+        }
+        self.check_path_dependencies(&state);
         let mut phases = PhaseDetailsBuilder::new();
         let code_out = state.apply(user_code.clone(), &code_info.nodes)?;
 
@@ -422,6 +659,100 @@ impl EvalContext {
         Ok(outputs)
     }
 
+    /// Reports how any build of dependencies that was running in the background went, if it has
+    /// finished. If `wait` is true, waits for it to finish first. If the build failed or was
+    /// interrupted, the dependencies that it was building are removed and an error is returned.
+    fn report_background_build(&mut self, wait: bool) -> Result<(), Error> {
+        let outcome = match self.module.take_background_build_outcome(wait) {
+            Some(outcome) => outcome,
+            None => return Ok(()),
+        };
+        let previous_deps = self.deps_before_background_build.take();
+        let problem = match outcome {
+            BackgroundBuildOutcome::Succeeded => {
+                let _ = self
+                    .stdout_sender
+                    .send("Finished building dependencies.".to_owned());
+                return Ok(());
+            }
+            BackgroundBuildOutcome::Interrupted => "was interrupted".to_owned(),
+            BackgroundBuildOutcome::Failed(errors) => format!("failed:\n{}", errors),
+        };
+        if let Some(previous_deps) = previous_deps {
+            let mut removed: Vec<&str> = self
+                .committed_state
+                .external_deps
+                .keys()
+                .filter(|name| {
+                    previous_deps.get(*name) != self.committed_state.external_deps.get(*name)
+                })
+                .map(String::as_str)
+                .collect();
+            removed.sort_unstable();
+            let removed = removed.join(", ");
+            self.committed_state.external_deps = previous_deps;
+            self.module.write_cargo_toml(&self.committed_state)?;
+            bail!(
+                "Building dependencies in the background {}\nThe following dependencies have been \
+                 removed: {}",
+                problem,
+                removed
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks whether the source of any of our path dependencies, direct or indirect, has changed
+    /// since we last compiled successfully. Cargo will rebuild changed dependencies, but any
+    /// variables that hold values of types from the old version of a crate may no longer be valid,
+    /// so we warn about those.
+    fn check_path_dependencies(&mut self, state: &ContextState) {
+        let mut current_fingerprints = Vec::new();
+        for dependency in self.module.path_dependencies().values() {
+            let fingerprint = dependency.current_fingerprint();
+            let changed = fingerprint != dependency.built_fingerprint;
+            current_fingerprints.push(fingerprint);
+            if !changed {
+                continue;
+            }
+            let name = &dependency.crate_name;
+            let _ = self
+                .stdout_sender
+                .send(format!("Source of `{}` changed. Rebuilding.", name));
+            let mut affected_variables: Vec<&str> = state
+                .variable_states
+                .iter()
+                .filter(|(_, variable_state)| type_refers_to_crate(&variable_state.type_name, name))
+                .map(|(variable_name, _)| variable_name.as_str())
+                .collect();
+            if !affected_variables.is_empty() {
+                affected_variables.sort_unstable();
+                let _ = self.stdout_sender.send(format!(
+                    "Warning: the following variables have types from `{}` and may be invalid now \
+                     that it has been rebuilt: {}",
+                    name,
+                    affected_variables.join(", ")
+                ));
+            }
+        }
+        // If we haven't yet built one of our path dependencies, we don't know what source it's
+        // built from, so we can't fingerprint it.
+        let all_built = state.path_dependency_dirs().iter().all(|dir| {
+            self.module
+                .path_dependencies()
+                .contains_key(&dir.canonicalize().unwrap_or_else(|_| dir.clone()))
+        });
+        let fingerprint = if all_built {
+            current_fingerprints.sort_unstable();
+            let mut hasher = StableHasher::default();
+            hasher.add_all(&current_fingerprints);
+            Some(hasher.finish())
+        } else {
+            None
+        };
+        self.module.set_path_dependencies_fingerprint(fingerprint);
+    }
+
     pub(crate) fn completions(
         &mut self,
         user_code: CodeBlock,
@@ -439,28 +770,131 @@ impl EvalContext {
         // but those errors don't effect the analysis needed for completions.
         state.config.expand_use_statements = false;
         let user_code = state.apply(user_code, nodes)?;
-        let code = state.analysis_code(user_code);
-        let wrapped_offset = code.user_offset_to_output_offset(offset)?;
+        let code = state.analysis_files(user_code);
+        let position = code.user_offset_to_position(offset)?;
 
         if state.config.debug_mode {
-            let mut s = code.code_string();
-            s.insert_str(wrapped_offset, "<|>");
+            let mut s = code.block(position.file).code_string();
+            s.insert_str(position.offset, "<|>");
             println!("=========\n{}\n==========", s);
         }
 
-        self.analyzer.set_source(code.code_string())?;
-        let mut completions = self.analyzer.completions(wrapped_offset)?;
-        completions.start_offset = code.output_offset_to_user_offset(completions.start_offset)?;
-        completions.end_offset = code.output_offset_to_user_offset(completions.end_offset)?;
+        self.set_analysis_source(&code, &state)?;
+        let mut completions = self.analyzer.completions(position)?;
+        completions.start_offset =
+            code.output_offset_to_user_offset(position.file, completions.start_offset)?;
+        completions.end_offset =
+            code.output_offset_to_user_offset(position.file, completions.end_offset)?;
         // Filter internal identifiers.
         completions.completions.retain(|c| {
             c.code != "evcxr_variable_store"
                 && c.code != "evcxr_internal_runtime"
                 && c.code != "evcxr_analysis_wrapper"
+                && c.code != crate::rust_analyzer::CELL_MODULE
         });
+        for import in completions.completions.iter().flat_map(|c| &c.imports) {
+            if let Some(name) = imported_name(import) {
+                let candidates = self.pending_imports.entry(name.to_owned()).or_default();
+                if !candidates.contains(import) {
+                    candidates.push(import.to_owned());
+                }
+            }
+        }
         Ok(completions)
     }
 
+    pub(crate) fn hover(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        nodes: &[SyntaxNode],
+        offset: usize,
+    ) -> Result<Option<Hover>> {
+        // As for completions, we want the code to be as close as possible to what the user wrote.
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let user_code = state.apply(user_code, nodes)?;
+        let code = state.analysis_files(user_code);
+        let position = code.user_offset_to_position(offset)?;
+        self.set_analysis_source(&code, &state)?;
+        let mut hover = match self.analyzer.hover(position)? {
+            Some(hover) => hover,
+            None => return Ok(None),
+        };
+        hover.start_offset =
+            code.output_offset_to_user_offset(position.file, hover.start_offset)?;
+        hover.end_offset = code.output_offset_to_user_offset(position.file, hover.end_offset)?;
+        Ok(Some(hover))
+    }
+
+    pub(crate) fn signature_help(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        nodes: &[SyntaxNode],
+        offset: usize,
+    ) -> Result<Option<SignatureHelp>> {
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let user_code = state.apply(user_code, nodes)?;
+        let code = state.analysis_files(user_code);
+        let position = code.user_offset_to_position(offset)?;
+        self.set_analysis_source(&code, &state)?;
+        self.analyzer.signature_help(position)
+    }
+
+    /// Returns what each of the macro calls at `offsets` within `user_code` expands to.
+    pub(crate) fn expand_macros(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        nodes: &[SyntaxNode],
+        offsets: &[usize],
+    ) -> Result<Vec<Option<MacroExpansion>>> {
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let user_code = state.apply(user_code, nodes)?;
+        let code = state.analysis_files(user_code);
+        self.set_analysis_source(&code, &state)?;
+        offsets
+            .iter()
+            .map(|offset| {
+                self.analyzer
+                    .expand_macro(code.user_offset_to_position(*offset)?)
+            })
+            .collect()
+    }
+
+    /// Returns the type of `expression` as inferred by rust-analyzer in the context of `state`,
+    /// without compiling or running anything.
+    pub(crate) fn expression_type(
+        &mut self,
+        expression: &str,
+        mut state: ContextState,
+    ) -> Result<TypeName, Error> {
+        const PROBE_VARIABLE: &str = "evcxr_type_probe";
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let probe = format!("let {} = ({});", PROBE_VARIABLE, expression);
+        let (user_code, code_info) = CodeBlock::from_original_user_code(&probe);
+        let user_code = state.apply(user_code, &code_info.nodes)?;
+        let code = state.analysis_files(user_code);
+        self.set_analysis_source(&code, &state)?;
+        Ok(self
+            .analyzer
+            .top_level_variables("evcxr_analysis_wrapper")
+            .remove(PROBE_VARIABLE)
+            .map_or(TypeName::Unknown, |info| info.type_name))
+    }
+
+    /// Compiles the items defined so far, asking rustc to also write out `kind`, and returns what
+    /// it wrote. The current optimization level and rustflags are used.
+    pub(crate) fn emit(&mut self, state: &ContextState, kind: EmitKind) -> Result<String, Error> {
+        self.write_cargo_toml(state)?;
+        let code = state.code_to_compile(CodeBlock::new(), CompilationMode::NoCatch);
+        self.module.emit(&code, &state.config, kind)
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
         self.module.last_source()
     }
@@ -511,12 +945,19 @@ impl EvalContext {
 
     pub fn reset_config(&mut self) {
         self.committed_state.config = self.initial_config.clone();
+        self.module
+            .set_committed_pinned_lockfile(self.committed_state.pinned_lockfile());
     }
 
     pub fn process_handle(&self) -> Arc<Mutex<std::process::Child>> {
         self.child_process.process_handle()
     }
 
+    /// Returns a handle that can be used to cancel a build while it's running.
+    pub fn build_cancellation_handle(&self) -> BuildCancellationHandle {
+        self.module.build_cancellation_handle()
+    }
+
     fn restart_child_process(&mut self) -> Result<(), Error> {
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
@@ -524,7 +965,75 @@ impl EvalContext {
         Ok(())
     }
 
-    pub(crate) fn last_compile_dir(&self) -> &Path {
+    /// Returns the contents of the workspace's Cargo.lock. Cargo.toml should have been written
+    /// first.
+    pub(crate) fn lockfile_contents(&self, state: &ContextState) -> Result<String, Error> {
+        // Running cargo metadata ensures that Cargo.lock exists and is up-to-date.
+        state.resolved_dependencies()?;
+        self.module.lockfile_contents()
+    }
+
+    /// Pins the versions of our dependencies in `state` to those in `contents`, the contents of a
+    /// Cargo.lock. If cargo doesn't accept the lockfile, e.g. because in offline mode, some of the
+    /// pinned versions aren't available, then our previous Cargo.lock is put back and `state` is
+    /// left unchanged.
+    pub(crate) fn load_lockfile(
+        &mut self,
+        contents: String,
+        state: &mut ContextState,
+    ) -> Result<(), Error> {
+        let previous_lockfile = self.module.lockfile_contents().ok();
+        let mut new_state = state.clone();
+        new_state.set_pinned_lockfile(contents);
+        self.module.write_cargo_toml(&new_state)?;
+        if let Err(error) = new_state.resolved_dependencies() {
+            self.module.restore_lockfile(previous_lockfile.as_deref())?;
+            self.module.write_cargo_toml(state)?;
+            return Err(error);
+        }
+        *state = new_state;
+        Ok(())
+    }
+
+    /// Compiles `source` as a proc-macro crate named `name` and adds it as a dependency in
+    /// `state`.
+    pub(crate) fn define_proc_macro(
+        &mut self,
+        name: &str,
+        source: &str,
+        state: &mut ContextState,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<(), Error> {
+        if name == crate::module::CRATE_NAME {
+            bail!("`{}` is reserved. Please pick another name", name);
+        }
+        if state.external_deps.contains_key(name)
+            && !state.proc_macro_crates().iter().any(|n| n == name)
+        {
+            bail!("`{}` is already a dependency", name);
+        }
+        if !state.config.proc_macro_crates.iter().any(|n| n == name) {
+            state.config.proc_macro_crates.push(name.to_owned());
+        }
+        self.module.write_cargo_toml(state)?;
+        self.module.build_proc_macro_crate(
+            name,
+            source,
+            &state.proc_macro_cargo_deps(),
+            &state.config,
+            callbacks.build_progress,
+        )?;
+        let crate_dir = self.module.proc_macro_crate_dir(name);
+        state.add_dep(
+            name,
+            &format!(
+                "{{ path = \"{}\" }}",
+                crate_dir.to_string_lossy().replace('\\', "\\\\")
+            ),
+        )
+    }
+
+    pub(crate) fn last_compile_dir(&self) -> PathBuf {
         self.module.crate_dir()
     }
 
@@ -535,6 +1044,8 @@ impl EvalContext {
         }
         state.stored_variable_states = state.variable_states.clone();
         state.commit_old_user_code();
+        self.module
+            .set_committed_pinned_lockfile(state.pinned_lockfile());
         self.committed_state = state;
     }
 
@@ -546,7 +1057,15 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
         self.write_cargo_toml(state)?;
-        self.fix_variable_types(state, state.analysis_code(user_code.clone()))?;
+        if let Err(error) = self.fix_variable_types(state, state.analysis_files(user_code.clone()))
+        {
+            // A variable's type may come from a name that needs an import from a completion that we
+            // offered, in which case we can't determine it until we've added the import.
+            if !self.add_pending_imports_for_errors(&user_code, state, phases, callbacks)? {
+                return Err(error);
+            }
+            self.fix_variable_types(state, state.analysis_files(user_code.clone()))?;
+        }
         // In some circumstances we may need a few tries before we get the code right. Note that
         // we'll generally give up sooner than this if there's nothing left that we think we can
         // fix. The limit is really to prevent retrying indefinitely in case our "fixing" of things
@@ -625,7 +1144,9 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<ExecutionArtifacts, Error> {
         let code = state.code_to_compile(user_code, compilation_mode);
-        let so_file = self.module.compile(&code, &state.config)?;
+        let so_file = self
+            .module
+            .compile(&code, &state.config, callbacks.build_progress)?;
 
         if compilation_mode == CompilationMode::NoCatchExpectError {
             // Uh-oh, caller was expecting an error, return OK and the caller can return the
@@ -634,7 +1155,11 @@ impl EvalContext {
                 output: EvalOutputs::new(),
             });
         }
-        phases.phase_complete("Final compile");
+        if so_file.cache_hit {
+            phases.phase_complete("Cache hit");
+        } else {
+            phases.phase_complete("Final compile");
+        }
 
         let output = self.run_and_capture_output(state, &so_file, callbacks)?;
         Ok(ExecutionArtifacts { output })
@@ -645,19 +1170,36 @@ impl EvalContext {
         Ok(())
     }
 
+    fn set_analysis_source(&mut self, code: &AnalysisCode, state: &ContextState) -> Result<()> {
+        self.analyzer.set_config(&state.config);
+        self.analyzer
+            .set_source(code.lib.code_string(), code.cell.code_string())
+    }
+
     fn fix_variable_types(
         &mut self,
         state: &mut ContextState,
-        code: CodeBlock,
+        code: AnalysisCode,
     ) -> Result<(), Error> {
-        self.analyzer.set_source(code.code_string())?;
+        self.set_analysis_source(&code, state)?;
+        let mut variables = self.analyzer.top_level_variables("evcxr_analysis_wrapper");
+        // Proc macros from our dependencies can't be expanded until their build scripts, which run
+        // in the background, have finished. So if we couldn't determine a type, it may be worth
+        // waiting for them and trying again.
+        if variables
+            .values()
+            .any(|info| info.type_name == TypeName::Unknown)
+            && self.analyzer.wait_for_build_scripts()
+        {
+            variables = self.analyzer.top_level_variables("evcxr_analysis_wrapper");
+        }
         for (
             variable_name,
             VariableInfo {
                 type_name,
                 is_mutable,
             },
-        ) in self.analyzer.top_level_variables("evcxr_analysis_wrapper")
+        ) in variables
         {
             // We don't want to try to store record evcxr_variable_store into itself, so we ignore
             // it.
@@ -708,6 +1250,7 @@ impl EvalContext {
         // things won't work if the path isn't UTF-8 - apparently that's a thing
         // on some platforms.
         let fn_name = state.current_user_fn_name();
+        self.module.build_cancellation_handle().stop_building()?;
         self.child_process.send(&format!(
             "LOAD_AND_RUN {} {}",
             so_file.path.to_string_lossy(),
@@ -837,6 +1380,14 @@ impl EvalContext {
                             self.write_cargo_toml(state)?;
                         }
                         fixed_errors.insert("Enabled async mode");
+                    } else if self.add_pending_import(error, state) {
+                        fixed_errors.insert("Added import");
+                    } else if (error.code() == Some("E0432") || error.code() == Some("E0433"))
+                        && state.config.auto_dep
+                    {
+                        if self.add_dep_for_unresolved_crate(error, state)? {
+                            fixed_errors.insert("Added dependency");
+                        }
                     } else if error.code() == Some("E0277") && !state.allow_question_mark {
                         state.allow_question_mark = true;
                         fixed_errors.insert("Allow question mark");
@@ -860,6 +1411,128 @@ impl EvalContext {
         }
         Ok(())
     }
+
+    /// Compiles `user_code` without running it, then adds any imports from completions that we
+    /// offered that would fix the resulting errors. Returns whether any imports were added.
+    fn add_pending_imports_for_errors(
+        &mut self,
+        user_code: &CodeBlock,
+        state: &mut ContextState,
+        phases: &mut PhaseDetailsBuilder,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<bool, Error> {
+        if self.pending_imports.is_empty() {
+            return Ok(false);
+        }
+        let errors = match self.try_run_statements(
+            user_code.clone(),
+            state,
+            CompilationMode::NoCatchExpectError,
+            phases,
+            callbacks,
+        ) {
+            Err(Error::CompilationErrors(errors)) => errors,
+            Err(Error::Interrupted) => return Err(Error::Interrupted),
+            _ => return Ok(false),
+        };
+        let mut added = false;
+        for error in &errors {
+            if self.add_pending_import(error, state) {
+                added = true;
+            }
+        }
+        Ok(added)
+    }
+
+    /// If `error` is due to use of a name that we offered a completion for that needed an import,
+    /// and there's only one import that it could have been, adds that import. Returns whether an
+    /// import was added.
+    fn add_pending_import(&self, error: &CompilationError, state: &mut ContextState) -> bool {
+        if !matches!(error.code(), Some("E0412" | "E0425" | "E0433")) {
+            return false;
+        }
+        let message = error.message();
+        let name = match message.split('`').nth(1) {
+            Some(name) => name,
+            None => return false,
+        };
+        match self.pending_imports.get(name).map(Vec::as_slice) {
+            Some([import]) if !state.items_by_name.contains_key(name) => {
+                state.add_import(name, import);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// If `error` is due to use of a crate that isn't a dependency, but which is available in the
+    /// local registry cache, adds the crate as a dependency. Returns whether a dependency was added.
+    fn add_dep_for_unresolved_crate(
+        &mut self,
+        error: &CompilationError,
+        state: &mut ContextState,
+    ) -> Result<bool, Error> {
+        let name = match unresolved_crate_name(&error.message()) {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        if state.external_deps.contains_key(&name) || state.items_by_name.contains_key(&name) {
+            return Ok(false);
+        }
+        let dep_config =
+            match crate::local_registry::find(&name).and_then(|krate| krate.dep_config(&name)) {
+                Some(dep_config) => dep_config,
+                None => return Ok(false),
+            };
+        // If the cached version can't be used, e.g. because in offline mode some of its
+        // dependencies aren't available, we leave the original error for the user to see.
+        if state.add_dep(&name, &dep_config).is_err() {
+            return Ok(false);
+        }
+        // Make sure that the new dependency is in Cargo.toml before we recompile.
+        self.write_cargo_toml(state)?;
+        let _ = self
+            .stdout_sender
+            .send(format!("Added dependency {} = {}", name, dep_config));
+        Ok(true)
+    }
+}
+
+/// Returns the name imported by a use statement of the form `use a::b::c;`.
+fn imported_name(use_statement: &str) -> Option<&str> {
+    use_statement
+        .strip_prefix("use ")?
+        .strip_suffix(';')?
+        .rsplit("::")
+        .next()
+}
+
+/// Returns whether `type_name` refers to something in the crate `crate_name`. For example,
+/// `Vec<foo::Bar>` refers to `foo`, but not to `oo` or to `foo_bar`.
+fn type_refers_to_crate(type_name: &str, crate_name: &str) -> bool {
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+    type_name
+        .match_indices(&format!("{}::", crate_name))
+        .any(|(index, _)| {
+            let before = &type_name[..index];
+            // A leading `::` is fine, but not one that follows another path segment.
+            !before
+                .strip_suffix("::")
+                .unwrap_or(before)
+                .ends_with(is_ident_char)
+        })
+}
+
+/// Returns the name of the crate that an unresolved import or path error refers to.
+fn unresolved_crate_name(message: &str) -> Option<String> {
+    static UNRESOLVED_CRATE: OnceCell<Regex> = OnceCell::new();
+    let unresolved_crate = UNRESOLVED_CRATE.get_or_init(|| {
+        Regex::new("(?:undeclared crate or module|unresolved import) `([A-Za-z_][A-Za-z0-9_]*)")
+            .unwrap()
+    });
+    unresolved_crate
+        .captures(message)
+        .map(|captures| captures[1].to_owned())
 }
 
 fn non_persistable_type_error(variable_name: &str, actual_type: &str) -> Result<(), Error> {
@@ -932,6 +1605,39 @@ impl PhaseDetailsBuilder {
     }
 }
 
+/// Code for rust-analyzer to analyze, split between the files described by `AnalysisFile`.
+struct AnalysisCode {
+    lib: CodeBlock,
+    cell: CodeBlock,
+}
+
+impl AnalysisCode {
+    fn block(&self, file: AnalysisFile) -> &CodeBlock {
+        match file {
+            AnalysisFile::Lib => &self.lib,
+            AnalysisFile::Cell => &self.cell,
+        }
+    }
+
+    /// Converts an offset within the user's code into a position within one of the files.
+    fn user_offset_to_position(&self, user_code_offset: usize) -> Result<Position> {
+        if let Ok(offset) = self.cell.user_offset_to_output_offset(user_code_offset) {
+            return Ok(Position {
+                file: AnalysisFile::Cell,
+                offset,
+            });
+        }
+        Ok(Position {
+            file: AnalysisFile::Lib,
+            offset: self.lib.user_offset_to_output_offset(user_code_offset)?,
+        })
+    }
+
+    fn output_offset_to_user_offset(&self, file: AnalysisFile, offset: usize) -> Result<usize> {
+        self.block(file).output_offset_to_user_offset(offset)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
     pub content_by_mime_type: HashMap<String, String>,
@@ -972,6 +1678,7 @@ impl EvalOutputs {
                 .or_default()
                 .push_str(&content);
         }
+        self.phases.extend(other.phases);
     }
 }
 
@@ -1063,6 +1770,14 @@ impl ContextState {
         self.config.offline_mode = value;
     }
 
+    pub fn set_auto_dep(&mut self, value: bool) {
+        self.config.auto_dep = value;
+    }
+
+    pub fn auto_dep(&self) -> bool {
+        self.config.auto_dep
+    }
+
     pub fn set_sccache(&mut self, enabled: bool) -> Result<(), Error> {
         self.config.set_sccache(enabled)
     }
@@ -1104,6 +1819,14 @@ impl ContextState {
         &self.config.linker
     }
 
+    pub fn set_rustflags(&mut self, rustflags: Vec<String>) {
+        self.config.rustflags = rustflags;
+    }
+
+    pub fn rustflags(&self) -> &[String] {
+        &self.config.rustflags
+    }
+
     pub fn preserve_vars_on_panic(&self) -> bool {
         self.config.preserve_vars_on_panic
     }
@@ -1143,6 +1866,157 @@ impl ContextState {
         self.config.output_format = output_format;
     }
 
+    pub(crate) fn pinned_lockfile(&self) -> Option<&str> {
+        self.config.pinned_lockfile.as_deref()
+    }
+
+    pub(crate) fn patch_tables(&self) -> String {
+        self.config.patch_tables()
+    }
+
+    pub(crate) fn cargo_config_contents(&self) -> String {
+        self.config.cargo_config_contents()
+    }
+
+    /// Pins dependency versions to those in the supplied Cargo.lock contents.
+    pub fn set_pinned_lockfile(&mut self, contents: String) {
+        self.config.pinned_lockfile = Some(contents);
+    }
+
+    /// Overrides the crate `name` from `source` (e.g. "crates-io" or a git URL) with the specified
+    /// dependency, as per a `[patch]` table.
+    pub fn add_patch(&mut self, source: &str, name: &str, spec: &str) -> Result<(), Error> {
+        let external = ExternalCrate::new(name.to_owned(), spec.to_owned())?;
+        self.config
+            .patches
+            .entry(source.to_owned())
+            .or_default()
+            .insert(external.name, external.config);
+        Ok(())
+    }
+
+    /// Replaces the package identified by `package` (e.g. "foo:1.0.0") with the specified
+    /// dependency, as per a `[replace]` table.
+    pub fn add_replacement(&mut self, package: &str, spec: &str) -> Result<(), Error> {
+        let external = ExternalCrate::new(package.to_owned(), spec.to_owned())?;
+        self.config
+            .replacements
+            .insert(external.name, external.config);
+        Ok(())
+    }
+
+    /// Removes the entry for `name` from the `[patch]` table for `source`.
+    pub fn remove_patch(&mut self, source: &str, name: &str) -> Result<(), Error> {
+        let patches = match self.config.patches.get_mut(source) {
+            Some(patches) => patches,
+            None => bail!("No patches for `{}`", source),
+        };
+        if patches.remove(name).is_none() {
+            bail!("No patch for `{}` in `{}`", name, source);
+        }
+        if patches.is_empty() {
+            self.config.patches.remove(source);
+        }
+        Ok(())
+    }
+
+    /// Removes the entry for `package` from the `[replace]` table.
+    pub fn remove_replacement(&mut self, package: &str) -> Result<(), Error> {
+        if self.config.replacements.remove(package).is_none() {
+            bail!("No replacement for `{}`", package);
+        }
+        Ok(())
+    }
+
+    /// Adds a line of cargo configuration, e.g. `source.crates-io.replace-with = "mirror"`. The
+    /// line is rejected if cargo can't parse the resulting configuration.
+    pub fn add_cargo_config(&mut self, line: &str) -> Result<(), Error> {
+        if self.config.cargo_config.iter().any(|l| l == line) {
+            return Ok(());
+        }
+        let mut new_config = self.config.clone();
+        new_config.cargo_config.push(line.to_owned());
+        crate::cargo_metadata::validate_cargo_config(
+            &new_config.cargo_config_contents(),
+            &new_config,
+        )?;
+        self.config = new_config;
+        Ok(())
+    }
+
+    /// Removes any lines of cargo configuration that set `key`.
+    pub fn remove_cargo_config(&mut self, key: &str) -> Result<(), Error> {
+        let len_before = self.config.cargo_config.len();
+        self.config
+            .cargo_config
+            .retain(|line| line.split('=').next().map(str::trim) != Some(key));
+        if self.config.cargo_config.len() == len_before {
+            bail!("No cargo configuration for `{}`", key);
+        }
+        Ok(())
+    }
+
+    /// Links the native library `name` into subsequently compiled code. `kind` is as per rustc's
+    /// `-l`, e.g. "static" or "dylib". If `search_path` is given, it's added to the directories
+    /// searched for native libraries.
+    pub fn add_native_lib(
+        &mut self,
+        name: &str,
+        kind: Option<&str>,
+        search_path: Option<&Path>,
+    ) -> Result<(), Error> {
+        if let Some(search_path) = search_path {
+            let search_path = match search_path.canonicalize() {
+                Ok(path) => path,
+                Err(err) => bail!("{}: {:?}", err, search_path),
+            };
+            if !self.config.native_lib_search_paths.contains(&search_path) {
+                self.config.native_lib_search_paths.push(search_path);
+            }
+        }
+        let lib = match kind {
+            Some(kind) => format!("{}={}", kind, name),
+            None => name.to_owned(),
+        };
+        if !self.config.native_libs.contains(&lib) {
+            self.config.native_libs.push(lib);
+        }
+        Ok(())
+    }
+
+    /// Stops linking the native library `name`, whatever kind it was added as.
+    pub fn remove_native_lib(&mut self, name: &str) -> Result<(), Error> {
+        let len_before = self.config.native_libs.len();
+        self.config
+            .native_libs
+            .retain(|lib| lib.rsplit('=').next() != Some(name));
+        if self.config.native_libs.len() == len_before {
+            bail!("Native library `{}` isn't linked", name);
+        }
+        Ok(())
+    }
+
+    /// Compiles the C source file at `source` into a static library that will be linked into
+    /// subsequently compiled code. Returns the name of the library.
+    pub fn add_c_dep(&mut self, source: &Path) -> Result<String, Error> {
+        let source = match source.canonicalize() {
+            Ok(path) => path,
+            Err(err) => bail!("{}: {:?}", err, source),
+        };
+        let lib_name = crate::module::compile_c_dep(&source, &self.config)?;
+        self.config.c_deps.insert(source, lib_name.clone());
+        Ok(lib_name)
+    }
+
+    /// Stops linking the library compiled from the C source file at `source`.
+    pub fn remove_c_dep(&mut self, source: &Path) -> Result<(), Error> {
+        let source = source.canonicalize().unwrap_or_else(|_| source.to_owned());
+        if self.config.c_deps.remove(&source).is_none() {
+            bail!("{:?} isn't a C dependency", source);
+        }
+        Ok(())
+    }
+
     pub fn set_toolchain(&mut self, value: &str) {
         self.config.toolchain = value.to_owned();
     }
@@ -1165,10 +2039,65 @@ impl ContextState {
         Ok(())
     }
 
+    /// Adds `use_statement`, which imports `name`, unless something named `name` is already
+    /// defined.
+    pub(crate) fn add_import(&mut self, name: &str, use_statement: &str) {
+        if !self.items_by_name.contains_key(name) {
+            self.items_by_name.insert(
+                name.to_owned(),
+                CodeBlock::new().other_user_code(use_statement.to_owned()),
+            );
+        }
+    }
+
+    /// Returns the version and features that cargo would select for the specified dependency,
+    /// without adding it.
+    pub(crate) fn resolve_dep(
+        &self,
+        dep: &str,
+        dep_config: &str,
+    ) -> Result<Option<ResolvedDependency>, Error> {
+        Ok(crate::cargo_metadata::resolve_dep(
+            dep,
+            dep_config,
+            &self.config,
+        )?)
+    }
+
+    /// Returns the directories of our direct path dependencies.
+    pub(crate) fn path_dependency_dirs(&self) -> Vec<PathBuf> {
+        self.external_deps
+            .values()
+            .filter_map(|krate| krate.path())
+            .map(Path::to_owned)
+            .collect()
+    }
+
+    /// Describes the crate in `dir` for `:load_crate`, running cargo with our configuration, e.g.
+    /// so that offline mode is respected.
+    pub(crate) fn local_crate(&self, dir: &Path) -> Result<LocalCrate, Error> {
+        Ok(crate::cargo_metadata::get_local_crate(&self.config, dir)?)
+    }
+
+    /// Returns our direct dependencies as resolved by cargo. Cargo.toml should have been written
+    /// first.
+    pub(crate) fn resolved_dependencies(&self) -> Result<Vec<ResolvedDependency>, Error> {
+        Ok(crate::cargo_metadata::get_resolved_dependencies(
+            &self.config,
+        )?)
+    }
+
+    /// Returns our full dependency tree, as rendered by `cargo tree`. Cargo.toml should have been
+    /// written first.
+    pub(crate) fn dependency_tree(&self) -> Result<String, Error> {
+        Ok(crate::cargo_metadata::get_dependency_tree(&self.config)?)
+    }
+
     /// Clears fields that aren't useful for inclusion in bug reports and which might give away
     /// things like usernames.
     pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
         self.config.crate_dir = PathBuf::from("redacted");
+        self.config.workspace_dir = PathBuf::from("redacted");
         if self.config.sccache.is_some() {
             self.config.sccache = Some(PathBuf::from("redacted"));
         }
@@ -1262,7 +2191,47 @@ impl ContextState {
                 && !new_state.external_deps.is_empty())
             || (self.items_by_name != new_state.items_by_name
                 && !new_state.items_by_name.is_empty())
-            || (self.config.sccache != new_state.config.sccache)
+            || self.build_config_changed(new_state)
+    }
+
+    /// Returns whether the only thing that differs between `self` and `new_state` that could cause
+    /// compilation to fail is the set of external dependencies.
+    fn only_dependencies_changed(&self, new_state: &ContextState) -> bool {
+        self.external_deps != new_state.external_deps
+            && self.extern_crate_stmts == new_state.extern_crate_stmts
+            && self.items_by_name == new_state.items_by_name
+            && !self.build_config_changed(new_state)
+    }
+
+    /// Returns whether any of the configuration that affects how cargo and rustc build our crate
+    /// differs between `self` and `new_state`.
+    fn build_config_changed(&self, new_state: &ContextState) -> bool {
+        let old = &self.config;
+        let new = &new_state.config;
+        old.sccache != new.sccache
+            || old.pinned_lockfile != new.pinned_lockfile
+            || old.patches != new.patches
+            || old.replacements != new.replacements
+            || old.cargo_config != new.cargo_config
+            || old.native_libs != new.native_libs
+            || old.native_lib_search_paths != new.native_lib_search_paths
+            || old.c_deps != new.c_deps
+            || old.rustflags != new.rustflags
+    }
+
+    pub(crate) fn proc_macro_crates(&self) -> &[String] {
+        &self.config.proc_macro_crates
+    }
+
+    /// Returns the dependencies for crates defined by `:proc_macro` cells. These get all our
+    /// dependencies other than the proc macro crates themselves.
+    pub(crate) fn proc_macro_cargo_deps(&self) -> String {
+        self.external_deps
+            .values()
+            .filter(|krate| !self.config.proc_macro_crates.contains(&krate.name))
+            .map(|krate| format!("{} = {}\n", krate.name, krate.config))
+            .collect::<Vec<_>>()
+            .join("")
     }
 
     pub(crate) fn format_cargo_deps(&self) -> String {
@@ -1281,14 +2250,37 @@ impl ContextState {
         }
     }
 
-    /// Returns code suitable for analysis purposes. Doesn't attempt to preserve runtime behavior.
+    /// Returns code for cargo check to check. This is the same code that we give to rust-analyzer,
+    /// but in a single file. Doesn't attempt to preserve runtime behavior.
     fn analysis_code(&self, user_code: CodeBlock) -> CodeBlock {
-        let mut code = CodeBlock::new()
+        self.analysis_items_code()
+            .add_all(self.analysis_wrapper_code(user_code))
+    }
+
+    /// Returns code for rust-analyzer to analyze, split into a crate root containing our items and
+    /// a module containing a function that wraps `user_code`.
+    fn analysis_files(&self, user_code: CodeBlock) -> AnalysisCode {
+        AnalysisCode {
+            lib: self
+                .analysis_items_code()
+                .generated(format!("mod {};", crate::rust_analyzer::CELL_MODULE)),
+            cell: CodeBlock::new()
+                .generated("use super::*;")
+                .add_all(self.analysis_wrapper_code(user_code)),
+        }
+    }
+
+    fn analysis_items_code(&self) -> CodeBlock {
+        CodeBlock::new()
             .generated("#![allow(unused_imports, unused_mut, dead_code)]")
             .add_all(self.attributes_code())
             .add_all(self.items_code())
             .add_all(self.error_trait_code(true))
             .generated("fn evcxr_variable_store<T: 'static>(_: T) {}")
+    }
+
+    fn analysis_wrapper_code(&self, user_code: CodeBlock) -> CodeBlock {
+        let mut code = CodeBlock::new()
             .generated("#[allow(unused_variables)]")
             .generated("async fn evcxr_analysis_wrapper(");
         for (var_name, state) in &self.stored_variable_states {
@@ -1840,6 +2832,17 @@ mod tests {
         assert_eq!(repl("Bar<async::foo::Baz>"), "Bar<r#async::foo::Baz>");
     }
 
+    #[test]
+    fn test_type_refers_to_crate() {
+        assert!(type_refers_to_crate("foo::Bar", "foo"));
+        assert!(type_refers_to_crate("Vec<foo::Bar>", "foo"));
+        assert!(type_refers_to_crate("::foo::Bar", "foo"));
+        assert!(type_refers_to_crate("(i32, foo::Bar)", "foo"));
+        assert!(!type_refers_to_crate("my_foo::Bar", "foo"));
+        assert!(!type_refers_to_crate("other::foo::Bar", "foo"));
+        assert!(!type_refers_to_crate("foo_bar::Baz", "foo"));
+    }
+
     fn create_state() -> ContextState {
         let config = Config::new(PathBuf::from("/dummy_path"));
         ContextState::new(config)
@@ -1871,4 +2874,54 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_patch_tables() {
+        let mut state = create_state();
+        state
+            .add_patch("crates-io", "foo", r#"{ git = "https://example.com/foo" }"#)
+            .unwrap();
+        state
+            .add_patch("https://example.com/bar", "bar", r#""1.0""#)
+            .unwrap();
+        state.add_replacement("baz:1.0.0", r#""1.0.1""#).unwrap();
+        assert_eq!(
+            state.patch_tables(),
+            r#"
+[patch.crates-io]
+foo = { git = "https://example.com/foo" }
+
+[patch."https://example.com/bar"]
+bar = "1.0"
+
+[replace]
+"baz:1.0.0" = "1.0.1"
+"#
+        );
+        state.remove_patch("crates-io", "foo").unwrap();
+        state.remove_replacement("baz:1.0.0").unwrap();
+        assert!(state.remove_patch("crates-io", "foo").is_err());
+        assert!(state.remove_replacement("baz:1.0.0").is_err());
+        assert_eq!(
+            state.patch_tables(),
+            r#"
+[patch."https://example.com/bar"]
+bar = "1.0"
+"#
+        );
+    }
+
+    #[test]
+    fn test_unresolved_crate_name() {
+        assert_eq!(
+            unresolved_crate_name("failed to resolve: use of undeclared crate or module `regex`")
+                .as_deref(),
+            Some("regex")
+        );
+        assert_eq!(
+            unresolved_crate_name("unresolved import `foo_bar`").as_deref(),
+            Some("foo_bar")
+        );
+        assert_eq!(unresolved_crate_name("mismatched types"), None);
+    }
 }
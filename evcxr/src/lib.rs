@@ -24,11 +24,14 @@ mod code_block;
 mod command_context;
 mod crash_guard;
 mod crate_config;
+mod emitted_code;
 mod eval_context;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
 mod item;
+mod local_registry;
 mod module;
+mod proc_macro_server;
 mod runtime;
 mod rust_analyzer;
 mod statement_splitter;
@@ -37,12 +40,18 @@ mod use_trees;
 pub use crate::command_context::CommandContext;
 pub use crate::errors::Error;
 pub use crate::errors::{CompilationError, Theme};
+pub use crate::eval_context::BuildProgress;
 pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::module::BuildCancellationHandle;
 pub use crate::runtime::runtime_hook;
+pub use rust_analyzer::Completion;
+pub use rust_analyzer::CompletionKind;
 pub use rust_analyzer::Completions;
+pub use rust_analyzer::Hover;
+pub use rust_analyzer::SignatureHelp;
 
 /// Return the directory that evcxr tools should use for their configuration.
 ///
@@ -54,3 +63,14 @@ pub fn config_dir() -> Option<std::path::PathBuf> {
         .map(std::path::PathBuf::from)
         .or_else(|| dirs::config_dir().map(|d| d.join("evcxr")))
 }
+
+/// Return the directory in which evcxr caches things between sessions, such as compiled code.
+///
+/// By default this is the `evcxr` subdirectory of whatever `dirs::cache_dir()`
+/// returns, but it can be overridden by the `EVCXR_CACHE_DIR` environment
+/// variable.
+pub(crate) fn cache_dir() -> Option<std::path::PathBuf> {
+    std::env::var_os("EVCXR_CACHE_DIR")
+        .map(std::path::PathBuf::from)
+        .or_else(|| dirs::cache_dir().map(|d| d.join("evcxr")))
+}
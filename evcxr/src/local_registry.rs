@@ -0,0 +1,308 @@
+// Copyright 2026 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A crate that's available in the local registry cache, together with all the versions of it that
+/// are available, newest first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CachedCrate {
+    pub(crate) name: String,
+    pub(crate) versions: Vec<String>,
+}
+
+impl CachedCrate {
+    /// Returns the newest version that isn't a pre-release, or if there are only pre-releases, the
+    /// newest of those.
+    pub(crate) fn newest_version(&self) -> Option<&str> {
+        self.versions
+            .iter()
+            .find(|version| VersionKey::new(version).is_release)
+            .or_else(|| self.versions.first())
+            .map(String::as_str)
+    }
+
+    /// Returns the configuration with which to add the newest version of this crate as a
+    /// dependency named `key`.
+    pub(crate) fn dep_config(&self, key: &str) -> Option<String> {
+        let version = self.newest_version()?;
+        if self.name == key {
+            Some(format!("\"{}\"", version))
+        } else {
+            Some(format!(
+                "{{ package = \"{}\", version = \"{}\" }}",
+                self.name, version
+            ))
+        }
+    }
+}
+
+/// Returns all crates that cargo has downloaded, sorted by name. When running offline, these are
+/// the only crates that can be used. Scanning the registry can be slow and is needed by every
+/// offline `:dep`, so we only scan it again once one of its directories has been modified, e.g.
+/// because cargo downloaded a crate.
+pub(crate) fn cached_crates() -> Arc<Vec<CachedCrate>> {
+    static LAST_SCAN: Mutex<Option<RegistryScan>> = Mutex::new(None);
+    match cargo_home() {
+        Some(cargo_home) => scan_if_modified(&mut LAST_SCAN.lock().unwrap(), &cargo_home),
+        None => Arc::default(),
+    }
+}
+
+/// Returns cached crates whose names contain `term`. Hyphens and underscores are treated as
+/// equivalent.
+pub(crate) fn search(term: &str) -> Vec<CachedCrate> {
+    let term = normalize_name(term);
+    cached_crates()
+        .iter()
+        .filter(|krate| normalize_name(&krate.name).contains(&term))
+        .cloned()
+        .collect()
+}
+
+/// Returns the cached crate with the specified name, if any. Hyphens and underscores are treated as
+/// equivalent, since `:dep foo_bar` might refer to a crate named `foo-bar`.
+pub(crate) fn find(name: &str) -> Option<CachedCrate> {
+    let name = normalize_name(name);
+    cached_crates()
+        .iter()
+        .find(|krate| normalize_name(&krate.name) == name)
+        .cloned()
+}
+
+/// The crates found by scanning the registry, together with the modification times of the
+/// directories that were scanned, so that we can tell when the scan is out of date.
+struct RegistryScan {
+    modified: Vec<(PathBuf, SystemTime)>,
+    crates: Arc<Vec<CachedCrate>>,
+}
+
+/// Returns the crates from `last_scan`, unless the registry in `cargo_home` has been modified since,
+/// in which case it's scanned again and `last_scan` is replaced.
+fn scan_if_modified(
+    last_scan: &mut Option<RegistryScan>,
+    cargo_home: &Path,
+) -> Arc<Vec<CachedCrate>> {
+    let modified = registry_dirs_modified(cargo_home);
+    if let Some(scan) = last_scan {
+        if scan.modified == modified {
+            return scan.crates.clone();
+        }
+    }
+    let crates = Arc::new(cached_crates_in(cargo_home));
+    *last_scan = Some(RegistryScan {
+        modified,
+        crates: crates.clone(),
+    });
+    crates
+}
+
+/// Returns the modification times of the directories that `cached_crates_in` reads. Adding or
+/// removing a crate changes the time of the registry's directory, while adding a registry adds an
+/// entry.
+fn registry_dirs_modified(cargo_home: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let mut modified = Vec::new();
+    for kind in ["cache", "src"] {
+        let kind_dir = cargo_home.join("registry").join(kind);
+        let registries = match std::fs::read_dir(&kind_dir) {
+            Ok(registries) => registries,
+            Err(_) => continue,
+        };
+        let mut dirs = vec![kind_dir];
+        dirs.extend(registries.filter_map(|entry| Some(entry.ok()?.path())));
+        for dir in dirs {
+            if let Ok(time) = std::fs::metadata(&dir).and_then(|metadata| metadata.modified()) {
+                modified.push((dir, time));
+            }
+        }
+    }
+    modified.sort();
+    modified
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    if let Some(cargo_home) = std::env::var_os("CARGO_HOME") {
+        return Some(PathBuf::from(cargo_home));
+    }
+    dirs::home_dir().map(|home| home.join(".cargo"))
+}
+
+fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Finds crates in `$CARGO_HOME/registry`. We look both at the downloaded .crate files in `cache`
+/// and at the extracted sources in `src`, since either may have been cleaned up independently.
+fn cached_crates_in(cargo_home: &Path) -> Vec<CachedCrate> {
+    let mut versions_by_name: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for kind in ["cache", "src"] {
+        let registries = match std::fs::read_dir(cargo_home.join("registry").join(kind)) {
+            Ok(registries) => registries,
+            Err(_) => continue,
+        };
+        for registry in registries.filter_map(|entry| entry.ok()) {
+            let entries = match std::fs::read_dir(registry.path()) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                let file_name = file_name.strip_suffix(".crate").unwrap_or(&file_name);
+                if let Some((name, version)) = parse_name_and_version(file_name) {
+                    let versions = versions_by_name.entry(name.to_owned()).or_default();
+                    if !versions.iter().any(|v| v == version) {
+                        versions.push(version.to_owned());
+                    }
+                }
+            }
+        }
+    }
+    versions_by_name
+        .into_iter()
+        .map(|(name, mut versions)| {
+            versions.sort_by(|a, b| VersionKey::new(b).cmp(&VersionKey::new(a)));
+            CachedCrate { name, versions }
+        })
+        .collect()
+}
+
+/// Splits something like "foo-bar-1.2.3-alpha" into ("foo-bar", "1.2.3-alpha").
+fn parse_name_and_version(name_and_version: &str) -> Option<(&str, &str)> {
+    static NAME_VERSION_RE: OnceCell<Regex> = OnceCell::new();
+    let name_version_re =
+        NAME_VERSION_RE.get_or_init(|| Regex::new(r"^(.+?)-([0-9]+\.[0-9]+\.[0-9]+.*)$").unwrap());
+    let captures = name_version_re.captures(name_and_version)?;
+    Some((captures.get(1)?.as_str(), captures.get(2)?.as_str()))
+}
+
+/// Something that orders versions such that later releases are greater and a pre-release sorts
+/// before the corresponding release. This is close enough to semver ordering for picking the newest
+/// of the versions we have.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct VersionKey<'a> {
+    numbers: Vec<u64>,
+    is_release: bool,
+    pre_release: &'a str,
+}
+
+impl<'a> VersionKey<'a> {
+    fn new(version: &'a str) -> VersionKey<'a> {
+        // Build metadata doesn't affect ordering.
+        let version = version.split('+').next().unwrap_or(version);
+        let (numbers, pre_release) = version.split_once('-').unwrap_or((version, ""));
+        VersionKey {
+            numbers: numbers.split('.').map(|n| n.parse().unwrap_or(0)).collect(),
+            is_release: pre_release.is_empty(),
+            pre_release,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cached_crates_in;
+    use super::parse_name_and_version;
+    use super::scan_if_modified;
+    use super::CachedCrate;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_name_and_version() {
+        assert_eq!(
+            parse_name_and_version("regex-1.5.4"),
+            Some(("regex", "1.5.4"))
+        );
+        assert_eq!(
+            parse_name_and_version("foo-2d-0.1.0-alpha.1"),
+            Some(("foo-2d", "0.1.0-alpha.1"))
+        );
+        assert_eq!(parse_name_and_version("not_a_crate"), None);
+    }
+
+    #[test]
+    fn test_cached_crates() {
+        let cargo_home = tempfile::tempdir().unwrap();
+        let registry = cargo_home.path().join("registry");
+        let cache = registry.join("cache").join("github.com-1ecc6299db9ec823");
+        let src = registry.join("src").join("github.com-1ecc6299db9ec823");
+        std::fs::create_dir_all(&cache).unwrap();
+        std::fs::create_dir_all(src.join("regex-1.10.0")).unwrap();
+        for file in [
+            "regex-1.9.1.crate",
+            "regex-1.10.0.crate",
+            "regex-2.0.0-alpha.crate",
+            "foo-bar-0.1.0.crate",
+        ] {
+            std::fs::write(cache.join(file), "").unwrap();
+        }
+        assert_eq!(
+            cached_crates_in(cargo_home.path()),
+            vec![
+                CachedCrate {
+                    name: "foo-bar".to_owned(),
+                    versions: vec!["0.1.0".to_owned()],
+                },
+                CachedCrate {
+                    name: "regex".to_owned(),
+                    versions: vec![
+                        "2.0.0-alpha".to_owned(),
+                        "1.10.0".to_owned(),
+                        "1.9.1".to_owned()
+                    ],
+                },
+            ]
+        );
+        let crates = cached_crates_in(cargo_home.path());
+        assert_eq!(crates[1].newest_version(), Some("1.10.0"));
+        assert_eq!(
+            crates[1].dep_config("regex").as_deref(),
+            Some(r#""1.10.0""#)
+        );
+        assert_eq!(
+            crates[0].dep_config("foo_bar").as_deref(),
+            Some(r#"{ package = "foo-bar", version = "0.1.0" }"#)
+        );
+    }
+
+    #[test]
+    fn rescan_after_registry_modified() {
+        let cargo_home = tempfile::tempdir().unwrap();
+        let cache = cargo_home.path().join("registry").join("cache");
+        std::fs::create_dir_all(cache.join("registry-a")).unwrap();
+        std::fs::write(cache.join("registry-a").join("regex-1.0.0.crate"), "").unwrap();
+        let mut last_scan = None;
+        let crates = scan_if_modified(&mut last_scan, cargo_home.path());
+        assert_eq!(crates.len(), 1);
+        assert!(Arc::ptr_eq(
+            &crates,
+            &scan_if_modified(&mut last_scan, cargo_home.path())
+        ));
+
+        std::fs::create_dir_all(cache.join("registry-b")).unwrap();
+        std::fs::write(cache.join("registry-b").join("foo-0.1.0.crate"), "").unwrap();
+        let crates = scan_if_modified(&mut last_scan, cargo_home.path());
+        assert_eq!(
+            crates.iter().map(|krate| &krate.name).collect::<Vec<_>>(),
+            vec!["foo", "regex"]
+        );
+    }
+}
@@ -12,18 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cargo_metadata;
 use crate::code_block::CodeBlock;
+use crate::emitted_code::EmitKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
+use crate::eval_context::BuildProgress;
 use crate::eval_context::Config;
 use crate::eval_context::ContextState;
 use once_cell::sync::OnceCell;
 use regex::Regex;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::HashMap;
 use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Child;
 use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::UNIX_EPOCH;
 
 fn shared_object_name_from_crate_name(crate_name: &str) -> String {
     if cfg!(target_os = "macos") {
@@ -35,6 +50,69 @@ fn shared_object_name_from_crate_name(crate_name: &str) -> String {
     }
 }
 
+/// Compiles the C source file at `source` into a static library in `config.c_deps_dir()` and
+/// returns the name of the library. The name includes a hash of the source, so that changes to the
+/// source result in a new library. Changes to headers included by the source aren't detected.
+pub(crate) fn compile_c_dep(source: &Path, config: &Config) -> Result<String, Error> {
+    if cfg!(windows) {
+        bail!(":cdep isn't currently supported on Windows");
+    }
+    let contents = match fs::read(source) {
+        Ok(contents) => contents,
+        Err(err) => bail!("Error reading '{:?}': {}", source, err),
+    };
+    let mut hasher = StableHasher::default();
+    hasher.add(source.to_string_lossy().as_bytes());
+    hasher.add(&contents);
+    let stem: String = source
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let lib_name = format!("evcxr_cdep_{}_{}", stem, hasher.finish());
+    let dir = config.c_deps_dir();
+    let archive = dir.join(format!("lib{}.a", lib_name));
+    if archive.exists() {
+        return Ok(lib_name);
+    }
+    create_dir(&dir)?;
+    let object = dir.join(format!("{}.o", lib_name));
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    run_native_tool(
+        Command::new(&compiler)
+            .arg("-c")
+            .arg("-fPIC")
+            .arg("-o")
+            .arg(&object)
+            .arg(source),
+    )?;
+    let archiver = std::env::var("AR").unwrap_or_else(|_| "ar".to_owned());
+    run_native_tool(
+        Command::new(&archiver)
+            .arg("crs")
+            .arg(&archive)
+            .arg(&object),
+    )?;
+    Ok(lib_name)
+}
+
+fn run_native_tool(command: &mut Command) -> Result<(), Error> {
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(err) => bail!("Error running {:?}: {}", command.get_program(), err),
+    };
+    if !output.status.success() {
+        bail!(
+            "{:?} failed:\n{}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
 fn create_dir(dir: &Path) -> Result<(), Error> {
     if let Err(err) = fs::create_dir_all(dir) {
         bail!("Error creating directory '{:?}': {}", dir, err);
@@ -90,20 +168,133 @@ fn rename_or_copy_so_file(src: &Path, dest: &Path) -> Result<(), Error> {
     Ok(())
 }
 
+/// How a build of our dependencies that ran in the background went.
+pub(crate) enum BackgroundBuildOutcome {
+    Succeeded,
+    Interrupted,
+    /// The build failed. Contains the errors reported by cargo.
+    Failed(String),
+}
+
 pub(crate) struct Module {
     pub(crate) tmpdir: PathBuf,
     build_num: i32,
     target: String,
+    /// A build of our dependencies that's running in the background, if any.
+    background_build: Option<JoinHandle<BackgroundBuildOutcome>>,
+    /// How the last background build went, if it's finished and nobody has asked yet.
+    background_build_outcome: Option<BackgroundBuildOutcome>,
+    build_cancellation: BuildCancellationHandle,
+    /// The pinned lockfile of our committed state. We only write a pinned lockfile until it's been
+    /// committed, since after that, cargo needs to be free to add any new dependencies to it.
+    committed_pinned_lockfile: Option<String>,
+    /// The contents of Cargo.toml when we last counted the packages that a build would need to
+    /// build, together with that count.
+    package_count: Option<(String, usize)>,
+    /// A fingerprint of the source of all our path dependencies. Cargo rebuilds path dependencies
+    /// when their source changes, so our cache of compiled code needs to take them into account.
+    /// None if we don't know the source of some of them, in which case we don't use the cache.
+    path_dependencies_fingerprint: Option<String>,
+    /// Our path dependencies, direct or indirect, as of the last successful compilation, keyed by
+    /// package directory.
+    path_dependencies: HashMap<PathBuf, PathDependency>,
+    /// Where we cache compiled code. This is generally shared with other sessions.
+    cache_dir: PathBuf,
+    /// The output of `rustc -vV`, keyed by the rustc path and toolchain that produced it.
+    rustc_versions: HashMap<(String, String), String>,
+}
+
+/// A crate on the local filesystem that we depend on, either directly or via another such crate.
+#[derive(Debug)]
+pub(crate) struct PathDependency {
+    /// The name by which Rust code refers to the crate.
+    pub(crate) crate_name: String,
+    /// The files that the crate was last built from, as reported by rustc, plus its Cargo.toml.
+    sources: Vec<PathBuf>,
+    /// The fingerprint of `sources` when the crate was last built.
+    pub(crate) built_fingerprint: String,
 }
 
-const CRATE_NAME: &str = "ctx";
+impl PathDependency {
+    /// Returns a fingerprint of the sizes and modification times of the files that the crate was
+    /// built from.
+    pub(crate) fn current_fingerprint(&self) -> String {
+        let mut hasher = StableHasher::default();
+        for source in &self.sources {
+            hasher.add(source.to_string_lossy().as_bytes());
+            if let Ok(metadata) = fs::metadata(source) {
+                hasher.add(metadata.len().to_le_bytes());
+                if let Ok(modified) = metadata.modified() {
+                    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    hasher.add(modified.as_nanos().to_le_bytes());
+                }
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// Hashes data in a way that, unlike `DefaultHasher`, doesn't change between builds of evcxr, so
+/// that the result can be used to name things that outlive a session, such as compiled code in our
+/// cache.
+#[derive(Default)]
+pub(crate) struct StableHasher(Sha256);
+
+impl StableHasher {
+    /// Adds `bytes`, prefixed by their length so that consecutive values can't run together.
+    pub(crate) fn add(&mut self, bytes: impl AsRef<[u8]>) {
+        let bytes = bytes.as_ref();
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+    }
+
+    /// Adds each of `values`, preceded by how many there are.
+    pub(crate) fn add_all<T: AsRef<[u8]>>(&mut self, values: impl IntoIterator<Item = T>) {
+        let values: Vec<T> = values.into_iter().collect();
+        self.add((values.len() as u64).to_le_bytes());
+        for value in values {
+            self.add(value);
+        }
+    }
+
+    /// Returns the first 128 bits of the hash as hex digits.
+    pub(crate) fn finish(self) -> String {
+        let hash = self.0.finalize();
+        format!(
+            "{:032x}",
+            u128::from_be_bytes(hash[..16].try_into().unwrap())
+        )
+    }
+}
+
+/// The name of the crate that we compile user code into. It's also the name of the directory within
+/// our workspace that contains that crate.
+pub(crate) const CRATE_NAME: &str = "ctx";
+
+/// The directory within our workspace that contains crates defined by `:proc_macro` cells.
+const PROC_MACROS_DIR: &str = "proc_macros";
+
+/// The maximum number of shared objects that we keep in the compilation cache.
+/// When exceeded, the oldest entries are removed.
+const MAX_CACHED_SO_FILES: usize = 200;
 
 impl Module {
-    pub(crate) fn new(tmpdir: PathBuf) -> Result<Module, Error> {
+    /// Creates a module that builds in `tmpdir`. Compiled code is cached in `cache_dir` if
+    /// specified, otherwise in `tmpdir`, in which case it's lost at the end of the session.
+    pub(crate) fn new(tmpdir: PathBuf, cache_dir: Option<PathBuf>) -> Result<Module, Error> {
         let module = Module {
+            cache_dir: cache_dir.unwrap_or_else(|| tmpdir.join("cache")),
             tmpdir,
             build_num: 0,
             target: get_host_target()?,
+            background_build: None,
+            background_build_outcome: None,
+            build_cancellation: BuildCancellationHandle::default(),
+            committed_pinned_lockfile: None,
+            package_count: None,
+            path_dependencies_fingerprint: None,
+            path_dependencies: HashMap::new(),
+            rustc_versions: HashMap::new(),
         };
         Ok(module)
     }
@@ -113,7 +304,12 @@ impl Module {
     }
 
     fn target_dir(&self) -> PathBuf {
-        self.tmpdir.join("target").join(&self.target)
+        self.workspace_target_dir().join(&self.target)
+    }
+
+    /// The target directory shared by all crates in our workspace.
+    fn workspace_target_dir(&self) -> PathBuf {
+        self.tmpdir.join("target")
     }
 
     fn so_path(&self) -> PathBuf {
@@ -122,24 +318,180 @@ impl Module {
     }
 
     fn src_dir(&self) -> PathBuf {
-        self.tmpdir.join("src")
+        self.crate_dir().join("src")
     }
 
-    pub(crate) fn crate_dir(&self) -> &Path {
+    /// The root of the Cargo workspace. Cargo.lock and the target directory live here.
+    pub(crate) fn workspace_dir(&self) -> &Path {
         &self.tmpdir
     }
 
+    /// The directory containing the crate that user code is compiled into.
+    pub(crate) fn crate_dir(&self) -> PathBuf {
+        self.workspace_dir().join(CRATE_NAME)
+    }
+
+    pub(crate) fn build_cancellation_handle(&self) -> BuildCancellationHandle {
+        self.build_cancellation.clone()
+    }
+
+    fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
         std::fs::read_to_string(self.src_dir().join("lib.rs"))
     }
 
-    // Writes Cargo.toml. Should be called before compile.
+    // Writes Cargo.toml for both the workspace and our crate. Should be called before compile.
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<(), Error> {
         write_file(
-            self.crate_dir(),
+            self.workspace_dir(),
+            "Cargo.toml",
+            &self.get_workspace_cargo_toml_contents(state),
+        )?;
+        write_file(
+            &self.crate_dir(),
             "Cargo.toml",
             &self.get_cargo_toml_contents(state),
-        )
+        )?;
+        write_file(
+            &self.workspace_dir().join(".cargo"),
+            "config.toml",
+            &state.cargo_config_contents(),
+        )?;
+        if let Some(pinned_lockfile) = state.pinned_lockfile() {
+            if Some(pinned_lockfile) != self.committed_pinned_lockfile.as_deref() {
+                write_file(self.workspace_dir(), "Cargo.lock", pinned_lockfile)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the pinned lockfile of state that has just been committed.
+    pub(crate) fn set_committed_pinned_lockfile(&mut self, pinned_lockfile: Option<&str>) {
+        self.committed_pinned_lockfile = pinned_lockfile.map(str::to_owned);
+    }
+
+    /// Replaces the workspace's Cargo.lock with `contents`, or removes it if `contents` is `None`.
+    pub(crate) fn restore_lockfile(&self, contents: Option<&str>) -> Result<(), Error> {
+        match contents {
+            Some(contents) => write_file(self.workspace_dir(), "Cargo.lock", contents),
+            None => {
+                let filename = self.workspace_dir().join("Cargo.lock");
+                if let Err(err) = fs::remove_file(&filename) {
+                    if err.kind() != std::io::ErrorKind::NotFound {
+                        bail!("Error removing '{:?}': {}", filename, err);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn set_path_dependencies_fingerprint(&mut self, fingerprint: Option<String>) {
+        self.path_dependencies_fingerprint = fingerprint;
+    }
+
+    /// Returns our path dependencies, direct or indirect, as of the last successful compilation,
+    /// keyed by package directory.
+    pub(crate) fn path_dependencies(&self) -> &HashMap<PathBuf, PathDependency> {
+        &self.path_dependencies
+    }
+
+    pub(crate) fn proc_macro_crate_dir(&self, name: &str) -> PathBuf {
+        self.workspace_dir().join(PROC_MACROS_DIR).join(name)
+    }
+
+    /// Writes and builds a proc-macro crate named `name` with the supplied source and dependencies.
+    /// The workspace Cargo.toml should already list the crate as a member. If the crate fails to
+    /// build, any previous version of it is put back, so that code using it keeps working.
+    pub(crate) fn build_proc_macro_crate(
+        &mut self,
+        name: &str,
+        source: &str,
+        dependencies: &str,
+        config: &Config,
+        build_progress: &dyn Fn(BuildProgress),
+    ) -> Result<(), Error> {
+        self.wait_for_background_build();
+        let crate_dir = self.proc_macro_crate_dir(name);
+        let previous_files: Vec<(PathBuf, Option<String>)> = [
+            crate_dir.join("Cargo.toml"),
+            crate_dir.join("src").join("lib.rs"),
+        ]
+        .into_iter()
+        .map(|path| {
+            let contents = fs::read_to_string(&path).ok();
+            (path, contents)
+        })
+        .collect();
+        write_file(
+            &crate_dir,
+            "Cargo.toml",
+            &format!(
+                r#"
+[package]
+name = "{}"
+version = "0.0.1"
+edition = "2021"
+
+[lib]
+proc-macro = true
+
+[dependencies]
+{}
+"#,
+                name, dependencies
+            ),
+        )?;
+        write_file(&crate_dir.join("src"), "lib.rs", source)?;
+        let mut command = config.cargo_command("build");
+        command
+            .arg("-p")
+            .arg(name)
+            .arg("--target")
+            .arg(&self.target)
+            .arg("--message-format=json")
+            .env("CARGO_TARGET_DIR", self.workspace_target_dir())
+            .env("RUSTC", &config.rustc_path);
+        let result =
+            match run_cargo_with_progress(command, 0, build_progress, &self.build_cancellation) {
+                Ok((_, true)) => Err(Error::Interrupted),
+                Ok((output, false)) if output.status.success() => Ok(()),
+                Ok((output, false)) => Err(Error::Message(format!(
+                    "Failed to compile proc macro crate `{}`:\n{}",
+                    name,
+                    build_failure_message(&output)
+                ))),
+                Err(err) => Err(Error::Message(format!(
+                    "Error running 'cargo build': {}",
+                    err
+                ))),
+            };
+        if result.is_err() {
+            if previous_files
+                .iter()
+                .all(|(_, contents)| contents.is_none())
+            {
+                let _ = fs::remove_dir_all(&crate_dir);
+            } else {
+                for (path, contents) in &previous_files {
+                    if let Some(contents) = contents {
+                        let _ = fs::write(path, contents);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub(crate) fn lockfile_contents(&self) -> Result<String, Error> {
+        let filename = self.workspace_dir().join("Cargo.lock");
+        match fs::read_to_string(&filename) {
+            Ok(contents) => Ok(contents),
+            Err(err) => bail!("Error reading '{:?}': {}", filename, err),
+        }
     }
 
     pub(crate) fn check(
@@ -147,6 +499,7 @@ impl Module {
         code_block: &CodeBlock,
         config: &Config,
     ) -> Result<Vec<CompilationError>, Error> {
+        self.wait_for_background_build();
         self.write_code(code_block)?;
         let output = config
             .cargo_command("check")
@@ -161,16 +514,70 @@ impl Module {
         Ok(errors)
     }
 
-    pub(crate) fn compile(
+    /// Starts building our dependencies in a background thread. This is done by compiling an empty
+    /// crate with the same settings that `compile` uses, so that by the time we next compile some
+    /// actual code, only that code needs to be built. Cargo.toml should already have been written.
+    /// How the build went can be obtained from `take_background_build_outcome`. `build_progress` is
+    /// called from the background thread.
+    pub(crate) fn start_background_build(
         &mut self,
-        code_block: &CodeBlock,
         config: &Config,
-    ) -> Result<SoFile, Error> {
-        let mut command = config.cargo_command("rustc");
-        if config.time_passes && config.toolchain != "nightly" {
-            bail!("time_passes option requires nightly compiler");
+        build_progress: impl Fn(BuildProgress) + Send + 'static,
+    ) -> Result<(), Error> {
+        self.wait_for_background_build();
+        let command = self.rustc_command(config);
+        write_file(&self.src_dir(), "lib.rs", "")?;
+        self.maybe_bump_lib_mtime();
+        let cancellation = self.build_cancellation.clone();
+        let config = config.clone();
+        let target = self.target.clone();
+        self.background_build = Some(std::thread::spawn(move || {
+            // Counting packages runs cargo metadata, so we do it here rather than holding up the
+            // cell that added the dependencies.
+            let total_packages = cargo_metadata::get_package_count(&config, &target).unwrap_or(0);
+            match run_cargo_with_progress(command, total_packages, &build_progress, &cancellation) {
+                Ok((_, true)) => BackgroundBuildOutcome::Interrupted,
+                Ok((out, false)) if out.status.success() => BackgroundBuildOutcome::Succeeded,
+                Ok((out, false)) => BackgroundBuildOutcome::Failed(build_failure_message(&out)),
+                Err(err) => {
+                    BackgroundBuildOutcome::Failed(format!("Error running 'cargo rustc': {}", err))
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Returns how the last background build went, if it's finished and its outcome hasn't
+    /// already been taken. If `wait` is true, blocks until any running background build finishes.
+    pub(crate) fn take_background_build_outcome(
+        &mut self,
+        wait: bool,
+    ) -> Option<BackgroundBuildOutcome> {
+        if wait
+            || self
+                .background_build
+                .as_ref()
+                .map(|handle| handle.is_finished())
+                .unwrap_or(false)
+        {
+            self.wait_for_background_build();
         }
+        self.background_build_outcome.take()
+    }
 
+    /// Blocks until any background build started by `start_background_build` has finished, then
+    /// records how it went.
+    fn wait_for_background_build(&mut self) {
+        if let Some(background_build) = self.background_build.take() {
+            self.background_build_outcome = Some(background_build.join().unwrap_or_else(|_| {
+                BackgroundBuildOutcome::Failed("Background build panicked".to_owned())
+            }));
+        }
+    }
+
+    /// Returns a command that will build our crate.
+    fn rustc_command(&self, config: &Config) -> Command {
+        let mut command = config.cargo_command("rustc");
         command
             .arg("--target")
             .arg(&self.target)
@@ -178,7 +585,7 @@ impl Module {
             .arg("--")
             .arg("-C")
             .arg("prefer-dynamic")
-            .env("CARGO_TARGET_DIR", "target")
+            .env("CARGO_TARGET_DIR", self.workspace_target_dir())
             .env("RUSTC", &config.rustc_path);
         if config.linker == "lld" {
             command
@@ -188,33 +595,284 @@ impl Module {
         if let Some(sccache) = &config.sccache {
             command.env("RUSTC_WRAPPER", sccache);
         }
+        for search_path in &config.native_lib_search_paths {
+            command.arg("-L").arg(search_path);
+        }
+        if !config.c_deps.is_empty() {
+            command
+                .arg("-L")
+                .arg(format!("native={}", config.c_deps_dir().to_string_lossy()));
+        }
+        for lib in &config.native_libs {
+            command.arg("-l").arg(lib);
+        }
+        for lib_name in config.c_deps.values() {
+            command.arg("-l").arg(format!("static={}", lib_name));
+        }
+        command.args(&config.rustflags);
+        command
+    }
+
+    pub(crate) fn compile(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+        build_progress: &dyn Fn(BuildProgress),
+    ) -> Result<SoFile, Error> {
+        if config.time_passes && config.toolchain != "nightly" {
+            bail!("time_passes option requires nightly compiler");
+        }
+        self.wait_for_background_build();
+        let mut command = self.rustc_command(config);
         if config.time_passes {
             command.arg("-Ztime-passes");
         }
         self.write_code(code_block)?;
-        let cargo_output = run_cargo(command, code_block)?;
+        // We don't use the cache when time_passes is on, since the user presumably wants to see
+        // what the compiler is doing.
+        let cached_so_file = if config.time_passes || self.path_dependencies_fingerprint.is_none() {
+            None
+        } else {
+            Some(self.cached_so_path(code_block, config))
+        };
+        if let Some(cached_so_file) = &cached_so_file {
+            if cached_so_file.exists() {
+                let so_file = self.next_so_path();
+                if let Err(err) = fs::copy(cached_so_file, &so_file) {
+                    bail!(
+                        "Error copying '{:?}' to '{:?}': {}",
+                        cached_so_file,
+                        so_file,
+                        err
+                    );
+                }
+                return Ok(SoFile {
+                    path: so_file,
+                    cache_hit: true,
+                });
+            }
+        }
+        let total_packages = self.package_count(config);
+        let cargo_output = run_cargo(
+            command,
+            code_block,
+            total_packages,
+            build_progress,
+            &self.build_cancellation,
+        )?;
         if config.time_passes {
             let output = String::from_utf8_lossy(&cargo_output.stderr);
             eprintln!("{}", output);
         }
-        self.build_num += 1;
-        let copied_so_file = self
-            .deps_dir()
-            .join(shared_object_name_from_crate_name(&format!(
-                "code_{}",
-                self.build_num
-            )));
+        self.path_dependencies = path_dependencies_from_cargo_output(&cargo_output);
+        let copied_so_file = self.next_so_path();
         // Every time we compile, the output file is the same. We need to
         // renamed it so that we have a unique filename, otherwise we wouldn't
         // be able to load the result of the next compilation. Also, on Windows,
         // a loaded dll gets locked, so we couldn't even compile a second time
         // if we didn't load a different file.
         rename_or_copy_so_file(&self.so_path(), &copied_so_file)?;
+        if cached_so_file.is_some() {
+            // Cargo may have updated Cargo.lock while building, so we recompute where to store the
+            // result, so that it's keyed by the versions of dependencies that it was built against.
+            let cached_so_file = self.cached_so_path(code_block, config);
+            self.add_to_cache(&copied_so_file, &cached_so_file);
+        }
         Ok(SoFile {
             path: copied_so_file,
+            cache_hit: false,
         })
     }
 
+    /// Compiles `code_block`, additionally asking rustc to write out `kind`, and returns what it
+    /// wrote. Everything is put in a single codegen unit so that there's just one file to read and
+    /// functions that nothing calls are still code generated.
+    pub(crate) fn emit(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+        kind: EmitKind,
+    ) -> Result<String, Error> {
+        self.wait_for_background_build();
+        // Remove output from any previous run, so that we can't mistake it for this run's output.
+        for path in self.emitted_files(kind) {
+            let _ = fs::remove_file(path);
+        }
+        let mut command = self.rustc_command(config);
+        command
+            .arg(format!("--emit={}", kind.rustc_emit_type()))
+            .arg("-C")
+            .arg("codegen-units=1")
+            .arg("-C")
+            .arg("link-dead-code")
+            .env("CARGO_INCREMENTAL", "0");
+        self.write_code(code_block)?;
+        run_cargo(command, code_block, 0, &|_| {}, &self.build_cancellation)?;
+        let path = match self.emitted_files(kind).pop() {
+            Some(path) => path,
+            None => bail!("rustc didn't write any {}", kind.rustc_emit_type()),
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(contents),
+            Err(err) => bail!("Error reading '{:?}': {}", path, err),
+        }
+    }
+
+    /// Returns the files of type `kind` that rustc has written for our crate.
+    fn emitted_files(&self, kind: EmitKind) -> Vec<PathBuf> {
+        let entries = match fs::read_dir(self.deps_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let stem = path.file_stem()?.to_str()?;
+                let is_ours = stem == CRATE_NAME || stem.strip_prefix(CRATE_NAME)?.starts_with('-');
+                (is_ours && path.extension()? == kind.file_extension()).then_some(path)
+            })
+            .collect()
+    }
+
+    /// Returns the number of packages that a build will need to build (or check are up-to-date),
+    /// including our own crate. This is only recomputed when Cargo.toml changes. If it can't be
+    /// determined, returns 0.
+    fn package_count(&mut self, config: &Config) -> usize {
+        let cargo_toml =
+            fs::read_to_string(self.crate_dir().join("Cargo.toml")).unwrap_or_default();
+        match &self.package_count {
+            Some((last_cargo_toml, count)) if *last_cargo_toml == cargo_toml => *count,
+            _ => {
+                let count = cargo_metadata::get_package_count(config, &self.target).unwrap_or(0);
+                self.package_count = Some((cargo_toml, count));
+                count
+            }
+        }
+    }
+
+    /// Returns a new, unique path to which a compiled shared object can be written.
+    fn next_so_path(&mut self) -> PathBuf {
+        self.build_num += 1;
+        self.deps_dir()
+            .join(shared_object_name_from_crate_name(&format!(
+                "code_{}",
+                self.build_num
+            )))
+    }
+
+    /// Returns the path at which the result of compiling `code_block` with the current Cargo.toml
+    /// and `config` would be stored in the cache. Anything that could affect the compiled output
+    /// needs to be included in the hash. Since the cache is shared between sessions, that includes
+    /// things that can't change within a session, such as the version of the compiler.
+    fn cached_so_path(&mut self, code_block: &CodeBlock, config: &Config) -> PathBuf {
+        let mut hasher = StableHasher::default();
+        hasher.add(env!("CARGO_PKG_VERSION"));
+        hasher.add(self.rustc_version(config));
+        hasher.add(code_block.code_string());
+        let mut manifest_dirs = vec![self.workspace_dir().to_owned(), self.crate_dir()];
+        manifest_dirs.extend(
+            config
+                .proc_macro_crates
+                .iter()
+                .map(|name| self.workspace_dir().join(PROC_MACROS_DIR).join(name)),
+        );
+        for dir in &manifest_dirs {
+            hasher.add(fs::read(dir.join("Cargo.toml")).unwrap_or_default());
+        }
+        for name in &config.proc_macro_crates {
+            let src = self
+                .workspace_dir()
+                .join(PROC_MACROS_DIR)
+                .join(name)
+                .join("src");
+            hasher.add(fs::read(src.join("lib.rs")).unwrap_or_default());
+        }
+        // The same Cargo.toml can resolve to different versions of dependencies, e.g. after
+        // `:lock load`.
+        hasher.add(self.lockfile_contents().unwrap_or_default());
+        hasher.add(
+            self.path_dependencies_fingerprint
+                .as_deref()
+                .unwrap_or_default(),
+        );
+        hasher.add_all(&config.cargo_config);
+        hasher.add_all(&config.native_libs);
+        hasher.add_all(
+            config
+                .native_lib_search_paths
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned()),
+        );
+        // The names of C dependencies include a hash of their source.
+        hasher.add_all(config.c_deps.values());
+        hasher.add_all(&config.rustflags);
+        hasher.add(&self.target);
+        hasher.add(&config.toolchain);
+        hasher.add(&config.rustc_path);
+        hasher.add(&config.linker);
+        // Rustc gives our output an rpath that's relative to where it's written, so it only works
+        // from directories at the same depth.
+        hasher.add(self.deps_dir().components().count().to_le_bytes());
+        self.cache_dir()
+            .join(shared_object_name_from_crate_name(&format!(
+                "code_{}",
+                hasher.finish()
+            )))
+    }
+
+    /// Returns the output of `rustc -vV` for the compiler that `config` builds with.
+    fn rustc_version(&mut self, config: &Config) -> &str {
+        self.rustc_versions
+            .entry((config.rustc_path.clone(), config.toolchain.clone()))
+            .or_insert_with(|| {
+                config
+                    .rustc_command()
+                    .arg("-vV")
+                    .output()
+                    .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                    .unwrap_or_default()
+            })
+    }
+
+    /// Stores a copy of `so_file` in the cache. Failure to do so isn't an error, it just means
+    /// that we'll need to compile again next time. Other sessions may be using the cache, so we
+    /// copy to a temporary file, then rename it, so that nobody sees a partially written file.
+    fn add_to_cache(&self, so_file: &Path, cached_so_file: &Path) {
+        if create_dir(self.cache_dir()).is_err() {
+            return;
+        }
+        let temp_file = match tempfile::NamedTempFile::new_in(self.cache_dir()) {
+            Ok(temp_file) => temp_file,
+            Err(_) => return,
+        };
+        if fs::copy(so_file, temp_file.path()).is_err()
+            || temp_file.persist(cached_so_file).is_err()
+        {
+            return;
+        }
+        self.evict_old_cache_entries();
+    }
+
+    fn evict_old_cache_entries(&self) {
+        let mut entries: Vec<_> = match fs::read_dir(self.cache_dir()) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let modified = entry.metadata().ok()?.modified().ok()?;
+                    Some((modified, entry.path()))
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        if entries.len() <= MAX_CACHED_SO_FILES {
+            return;
+        }
+        entries.sort();
+        for (_, path) in &entries[..entries.len() - MAX_CACHED_SO_FILES] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
     fn write_code(&self, code_block: &CodeBlock) -> Result<(), Error> {
         write_file(&self.src_dir(), "lib.rs", &code_block.code_string())?;
         self.maybe_bump_lib_mtime();
@@ -239,18 +897,16 @@ impl Module {
         );
     }
 
-    fn get_cargo_toml_contents(&self, state: &ContextState) -> String {
-        let crate_imports = state.format_cargo_deps();
+    fn get_workspace_cargo_toml_contents(&self, state: &ContextState) -> String {
+        let mut members = vec![format!("\"{}\"", CRATE_NAME)];
+        for name in state.proc_macro_crates() {
+            members.push(format!("\"{}/{}\"", PROC_MACROS_DIR, name));
+        }
         format!(
             r#"
-[package]
-name = "{}"
-version = "1.0.0"
-edition = "2021"
-
-[lib]
-crate-type = ["cdylib"]
-path = "src/lib.rs"
+[workspace]
+members = [{}]
+resolver = "2"
 
 [profile.dev]
 opt-level = {}
@@ -262,25 +918,365 @@ codegen-units = 16
 panic = 'unwind'
 incremental = true
 overflow-checks = true
+{}"#,
+            members.join(", "),
+            state.opt_level(),
+            state.patch_tables(),
+        )
+    }
+
+    fn get_cargo_toml_contents(&self, state: &ContextState) -> String {
+        let crate_imports = state.format_cargo_deps();
+        format!(
+            r#"
+[package]
+name = "{}"
+version = "1.0.0"
+edition = "2021"
+
+[lib]
+crate-type = ["cdylib"]
+path = "src/lib.rs"
 
 [dependencies]
 {}
 "#,
-            CRATE_NAME,
-            state.opt_level(),
-            crate_imports
+            CRATE_NAME, crate_imports
         )
     }
 }
 
-fn run_cargo(
+/// Allows a running build to be cancelled, e.g. from another thread in response to the user pressing
+/// Ctrl-C. Cancelling kills cargo and any compiler processes that it started. The build then fails
+/// with `Error::Interrupted`.
+#[derive(Clone, Default)]
+pub struct BuildCancellationHandle {
+    state: Arc<Mutex<RunningBuild>>,
+}
+
+#[derive(Default)]
+struct RunningBuild {
+    child: Option<Child>,
+    /// Whether `child` was killed by `cancel`.
+    child_killed: bool,
+    /// Whether we're evaluating some code and haven't yet started running it.
+    building: bool,
+    /// Whether `cancel` has been called since `building` was last set. Builds started after that
+    /// are killed straight away, so that an interruption between builds isn't lost.
+    cancelled: bool,
+}
+
+impl RunningBuild {
+    fn kill_child(&mut self) -> bool {
+        if let Some(child) = &mut self.child {
+            kill_process_group(child);
+            self.child_killed = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl BuildCancellationHandle {
+    /// Cancels the currently running build, if any. Returns whether there was a build to cancel.
+    /// While code is being evaluated, everything up until the code starts running counts as
+    /// building, even if cargo isn't running at the time.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.building {
+            state.cancelled = true;
+        }
+        state.kill_child() || state.building
+    }
+
+    /// Marks the start of evaluating some code. Any earlier cancellation is forgotten.
+    pub(crate) fn start_building(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.building = true;
+        state.cancelled = false;
+    }
+
+    /// Marks that we're done building and are about to run code, or have finished evaluating.
+    /// Fails with `Error::Interrupted` if we were cancelled since `start_building`.
+    pub(crate) fn stop_building(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.building = false;
+        if state.cancelled {
+            return Err(Error::Interrupted);
+        }
+        Ok(())
+    }
+
+    fn started(&self, child: Child) {
+        let mut state = self.state.lock().unwrap();
+        state.child = Some(child);
+        state.child_killed = false;
+        if state.cancelled {
+            state.kill_child();
+        }
+    }
+
+    /// Waits for the build to finish. Returns the exit status and whether the build was cancelled.
+    fn finished(&self) -> std::io::Result<(std::process::ExitStatus, bool)> {
+        // We poll rather than blocking in `wait`, since we mustn't hold the lock while waiting,
+        // otherwise `cancel` would block until the build finished. By the time we get here, cargo
+        // has closed its stdout, so it's generally about to exit anyway.
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                let child = match state.child.as_mut() {
+                    Some(child) => child,
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "Build process went missing",
+                        ))
+                    }
+                };
+                if let Some(status) = child.try_wait()? {
+                    state.child = None;
+                    return Ok((status, state.child_killed));
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
+
+/// Arranges for `command` to be run in a new process group. For cargo, this lets us kill it together
+/// with any processes that it starts, since killing just cargo would leave rustc running. It also
+/// means that when the user presses Ctrl-C in a terminal, the SIGINT only goes to us, so we can
+/// decide what to do about it.
+#[cfg(unix)]
+pub(crate) fn start_in_new_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // Safety: setpgid is async-signal-safe, so can be called between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn start_in_new_process_group(_command: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // Safety: kill has no memory safety requirements. A negative PID means the process group,
+    // which has the same ID as the child, since we made it the group leader.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Runs `command`, reporting progress as cargo tells us about each crate that it's built. Returns
+/// the output of the command and whether it was cancelled.
+fn run_cargo_with_progress(
     mut command: std::process::Command,
+    total_packages: usize,
+    build_progress: &dyn Fn(BuildProgress),
+    cancellation: &BuildCancellationHandle,
+) -> std::io::Result<(std::process::Output, bool)> {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    start_in_new_process_group(&mut command);
+    let mut child = command.spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    cancellation.started(child);
+    // We need to read stderr on a separate thread, otherwise cargo might block writing to stderr
+    // while we're waiting for it to write to stdout.
+    let stderr_thread = std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_end(&mut bytes);
+        }
+        bytes
+    });
+    let mut stdout_bytes = Vec::new();
+    if let Some(stdout) = stdout {
+        let mut progress = BuildProgress {
+            crates_built: 0,
+            total_crates: total_packages,
+            last_crate: String::new(),
+        };
+        for line in BufReader::new(stdout).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    // Kill cargo, since it might otherwise block writing output that we're no
+                    // longer reading, then make sure that it gets reaped.
+                    cancellation.state.lock().unwrap().kill_child();
+                    let _ = cancellation.finished();
+                    return Err(error);
+                }
+            };
+            if let Some((crate_name, fresh)) = built_crate_name(&line) {
+                progress.crates_built += 1;
+                progress.total_crates = progress.total_crates.max(progress.crates_built);
+                progress.last_crate = crate_name;
+                // Crates that were already up-to-date count towards our progress, but aren't
+                // worth reporting by themselves, since they take no time.
+                if !fresh {
+                    build_progress(progress.clone());
+                }
+            }
+            stdout_bytes.extend_from_slice(&line);
+            stdout_bytes.push(b'\n');
+        }
+    }
+    let stderr_bytes = stderr_thread.join().unwrap_or_default();
+    let (status, cancelled) = cancellation.finished()?;
+    Ok((
+        std::process::Output {
+            status,
+            stdout: stdout_bytes,
+            stderr: stderr_bytes,
+        },
+        cancelled,
+    ))
+}
+
+/// If `line` is a JSON message from cargo reporting that a crate has been built, returns the name of
+/// that crate and whether it was already up-to-date. Build scripts are ignored, since they're an
+/// implementation detail of their crate.
+fn built_crate_name(line: &[u8]) -> Option<(String, bool)> {
+    let message = json::parse(std::str::from_utf8(line).ok()?).ok()?;
+    if message["reason"].as_str() != Some("compiler-artifact")
+        || message["target"]["kind"]
+            .members()
+            .any(|kind| kind == "custom-build")
+    {
+        return None;
+    }
+    let fresh = message["fresh"].as_bool().unwrap_or(false);
+    message["target"]["name"]
+        .as_str()
+        .map(|name| (name.to_owned(), fresh))
+}
+
+/// Returns the path dependencies, direct or indirect, that cargo reported building, or finding
+/// already built, in `cargo_output`. They're keyed by package directory.
+fn path_dependencies_from_cargo_output(
+    cargo_output: &std::process::Output,
+) -> HashMap<PathBuf, PathDependency> {
+    let mut path_dependencies = HashMap::new();
+    for line in cargo_output.stdout.split(|byte| *byte == b'\n') {
+        let message = match std::str::from_utf8(line).map(json::parse) {
+            Ok(Ok(message)) => message,
+            _ => continue,
+        };
+        let is_path_package = message["package_id"]
+            .as_str()
+            .map_or(false, |id| id.contains("path+file://"));
+        let is_lib = message["target"]["kind"]
+            .members()
+            .any(|kind| kind == "lib" || kind == "rlib" || kind == "proc-macro");
+        if message["reason"].as_str() != Some("compiler-artifact") || !is_path_package || !is_lib {
+            continue;
+        }
+        let (crate_name, manifest_path) = match (
+            message["target"]["name"].as_str(),
+            message["manifest_path"].as_str(),
+        ) {
+            (Some(crate_name), Some(manifest_path)) => (crate_name, Path::new(manifest_path)),
+            _ => continue,
+        };
+        if crate_name == CRATE_NAME {
+            continue;
+        }
+        let mut sources = message["filenames"]
+            .members()
+            .filter_map(|filename| dep_info_path(Path::new(filename.as_str()?)))
+            .next()
+            .map(|dep_info| sources_from_dep_info(&dep_info))
+            .unwrap_or_default();
+        sources.push(manifest_path.to_owned());
+        let dir = manifest_path.parent().unwrap_or(manifest_path);
+        let mut dependency = PathDependency {
+            crate_name: crate_name.replace('-', "_"),
+            sources,
+            built_fingerprint: String::new(),
+        };
+        dependency.built_fingerprint = dependency.current_fingerprint();
+        path_dependencies.insert(
+            dir.canonicalize().unwrap_or_else(|_| dir.to_owned()),
+            dependency,
+        );
+    }
+    path_dependencies
+}
+
+/// Returns the path of the dep-info file that rustc writes alongside `artifact`, e.g.
+/// `deps/foo-1234.d` for `deps/libfoo-1234.rlib`.
+fn dep_info_path(artifact: &Path) -> Option<PathBuf> {
+    let stem = artifact.file_stem()?.to_str()?;
+    let stem = stem.strip_prefix("lib").unwrap_or(stem);
+    Some(artifact.with_file_name(format!("{}.d", stem)))
+}
+
+/// Reads a Makefile-style dep-info file as written by rustc and returns the source files that it
+/// lists. Returns an empty list if it can't be read.
+fn sources_from_dep_info(path: &Path) -> Vec<PathBuf> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    parse_dep_info(&contents)
+}
+
+fn parse_dep_info(contents: &str) -> Vec<PathBuf> {
+    // The first line is of the form `output: source1 source2`, with spaces in paths escaped.
+    let deps = match contents
+        .lines()
+        .next()
+        .and_then(|line| line.split_once(": "))
+    {
+        Some((_, deps)) => deps,
+        None => return Vec::new(),
+    };
+    let mut sources = Vec::new();
+    let mut current = String::new();
+    let mut chars = deps.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                current.push(' ');
+                chars.next();
+            }
+            ' ' => {
+                if !current.is_empty() {
+                    sources.push(PathBuf::from(std::mem::take(&mut current)));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        sources.push(PathBuf::from(current));
+    }
+    sources
+}
+
+fn run_cargo(
+    command: std::process::Command,
     code_block: &CodeBlock,
+    total_packages: usize,
+    build_progress: &dyn Fn(BuildProgress),
+    cancellation: &BuildCancellationHandle,
 ) -> Result<std::process::Output, Error> {
-    let cargo_output = match command.output() {
-        Ok(out) => out,
-        Err(err) => bail!("Error running 'cargo rustc': {}", err),
-    };
+    let cargo_output =
+        match run_cargo_with_progress(command, total_packages, build_progress, cancellation) {
+            Ok((_, true)) => bail!(Error::Interrupted),
+            Ok((out, false)) => out,
+            Err(err) => bail!("Error running 'cargo rustc': {}", err),
+        };
     if cargo_output.status.success() {
         Ok(cargo_output)
     } else {
@@ -302,6 +1298,25 @@ fn run_cargo(
     }
 }
 
+/// Returns the errors from the output of a failed cargo build, rendered as cargo would have
+/// printed them. Falls back to cargo's stderr if there were no compiler errors.
+fn build_failure_message(cargo_output: &std::process::Output) -> String {
+    let stdout = String::from_utf8_lossy(&cargo_output.stdout);
+    let rendered: Vec<String> = stdout
+        .lines()
+        .filter_map(|line| json::parse(line).ok())
+        .filter(|json| json["reason"] == "compiler-message" && json["message"]["level"] == "error")
+        .filter_map(|json| json["message"]["rendered"].as_str().map(str::to_owned))
+        .collect();
+    if rendered.is_empty() {
+        String::from_utf8_lossy(&cargo_output.stderr)
+            .trim()
+            .to_owned()
+    } else {
+        rendered.join("")
+    }
+}
+
 fn errors_from_cargo_output(
     cargo_output: &std::process::Output,
     code_block: &CodeBlock,
@@ -336,6 +1351,8 @@ fn errors_from_cargo_output(
 
 pub(crate) struct SoFile {
     pub(crate) path: PathBuf,
+    /// Whether this file was obtained from the cache rather than by running the compiler.
+    pub(crate) cache_hit: bool,
 }
 
 fn get_host_target() -> Result<String, Error> {
@@ -356,3 +1373,93 @@ fn get_host_target() -> Result<String, Error> {
         stderr
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::dep_info_path;
+    use super::parse_dep_info;
+    use super::run_cargo_with_progress;
+    use super::BuildCancellationHandle;
+    use super::StableHasher;
+    use crate::errors::Error;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    #[test]
+    fn dep_info() {
+        assert_eq!(
+            dep_info_path(Path::new("/t/deps/libfoo_bar-1234.rlib")),
+            Some(PathBuf::from("/t/deps/foo_bar-1234.d"))
+        );
+        assert_eq!(
+            parse_dep_info(
+                "/t/deps/foo-1234.d: /a/src/lib.rs /a\\ b/src/x.rs\n\n/a/src/lib.rs:\n/a\\ b/src/x.rs:\n"
+            ),
+            vec![PathBuf::from("/a/src/lib.rs"), PathBuf::from("/a b/src/x.rs")]
+        );
+        assert!(parse_dep_info("").is_empty());
+    }
+
+    #[test]
+    fn stable_hasher() {
+        let mut hasher = StableHasher::default();
+        hasher.add("evcxr");
+        // Hashes name files in caches that outlive a session, so mustn't change.
+        assert_eq!(hasher.finish(), "c2e47dfe49ec3f765fb75c1ca9622240");
+
+        let hash = |values: &[&str]| {
+            let mut hasher = StableHasher::default();
+            hasher.add_all(values);
+            hasher.finish()
+        };
+        assert_ne!(hash(&["ab", "c"]), hash(&["a", "bc"]));
+    }
+
+    #[test]
+    fn cancel_without_build() {
+        assert!(!BuildCancellationHandle::default().cancel());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_running_build() {
+        let handle = BuildCancellationHandle::default();
+        let canceller = {
+            let handle = handle.clone();
+            std::thread::spawn(move || {
+                while !handle.cancel() {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            })
+        };
+        // Closing stdout means that we'll be waiting for the process to exit when we cancel it.
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "exec >&-; sleep 60"]);
+        let (output, cancelled) = run_cargo_with_progress(command, 0, &|_| {}, &handle).unwrap();
+        canceller.join().unwrap();
+        assert!(cancelled);
+        assert!(!output.status.success());
+        // Once the build has finished, there's nothing left to cancel.
+        assert!(!handle.cancel());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_between_builds() {
+        let handle = BuildCancellationHandle::default();
+        handle.start_building();
+        assert!(handle.cancel());
+        // The cancellation sticks, so a build that starts afterwards is killed straight away.
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "exec >&-; sleep 60"]);
+        let (output, cancelled) = run_cargo_with_progress(command, 0, &|_| {}, &handle).unwrap();
+        assert!(cancelled);
+        assert!(!output.status.success());
+        assert!(matches!(handle.stop_building(), Err(Error::Interrupted)));
+        assert!(!handle.cancel());
+
+        // Starting again forgets the earlier cancellation.
+        handle.start_building();
+        assert!(handle.stop_building().is_ok());
+    }
+}
@@ -0,0 +1,603 @@
+// Copyright 2026 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use json::JsonValue;
+use ra_ap_base_db::ProcMacroExpansionError;
+use ra_ap_base_db::ProcMacroKind;
+use ra_ap_tt as tt;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::ChildStdout;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+/// The oldest version of the server's protocol that we can talk to. Version 5 added the kind and
+/// suffix of literals and whether identifiers are raw to the encoding of token trees.
+const MIN_PROTOCOL_VERSION: u32 = 5;
+
+/// The ID of tokens that don't come from the source that we're analyzing, such as those created by
+/// macros.
+const UNSPECIFIED_ID: u32 = !0;
+
+/// A proc macro server that's distributed with the Rust toolchain. It loads proc macro dylibs and
+/// expands the macros in them on our behalf. The client in the version of rust-analyzer that we use
+/// only speaks an older version of the server's protocol than current toolchains, so this is our
+/// own.
+#[derive(Debug)]
+pub(crate) struct ProcMacroServer {
+    path: PathBuf,
+    process: Mutex<ServerProcess>,
+}
+
+#[derive(Debug)]
+struct ServerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl ProcMacroServer {
+    /// Starts the server at `path` and checks that we can talk to it.
+    pub(crate) fn spawn(path: PathBuf) -> Result<ProcMacroServer> {
+        let mut child = std::process::Command::new(&path)
+            // The server refuses to run without this, since its protocol isn't stable.
+            .env("RUST_ANALYZER_INTERNALS_DO_NOT_USE", "this is unstable")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start proc macro server {:?}", path))?;
+        let process = ServerProcess {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        };
+        let server = ProcMacroServer {
+            path,
+            process: Mutex::new(process),
+        };
+        let version = server
+            .request("ApiVersionCheck", json::object! {})?
+            .as_u32()
+            .ok_or_else(|| anyhow!("Proc macro server didn't report its protocol version"))?;
+        if version < MIN_PROTOCOL_VERSION {
+            bail!(
+                "Proc macro server {:?} speaks version {} of its protocol, but we need version {} \
+                 or later",
+                server.path,
+                version,
+                MIN_PROTOCOL_VERSION
+            );
+        }
+        Ok(server)
+    }
+
+    /// Returns the names and kinds of the proc macros in the dylib at `dylib_path`.
+    pub(crate) fn list_macros(&self, dylib_path: &Path) -> Result<Vec<(String, ProcMacroKind)>> {
+        let response = self.request(
+            "ListMacros",
+            json::object! {"dylib_path" => dylib_path.to_string_lossy().as_ref()},
+        )?;
+        result_from_json(response)
+            .map_err(|error| anyhow!("Failed to load proc macro dylib: {}", error))?
+            .members()
+            .map(|proc_macro| {
+                let kind = match proc_macro[1].as_str() {
+                    Some("CustomDerive") => ProcMacroKind::CustomDerive,
+                    Some("Attr") => ProcMacroKind::Attr,
+                    // Older servers call these FuncLike.
+                    Some("Bang" | "FuncLike") => ProcMacroKind::FuncLike,
+                    _ => bail!("Unrecognised proc macro kind: {}", proc_macro[1]),
+                };
+                let name = proc_macro[0]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Proc macro has no name"))?;
+                Ok((name.to_owned(), kind))
+            })
+            .collect()
+    }
+
+    /// Expands the proc macro `macro_name` from the dylib at `dylib_path`. `attributes` are the
+    /// arguments to an attribute macro.
+    pub(crate) fn expand(
+        &self,
+        dylib_path: &Path,
+        macro_name: &str,
+        body: &tt::Subtree,
+        attributes: Option<&tt::Subtree>,
+        env: Vec<(String, String)>,
+    ) -> Result<tt::Subtree, ProcMacroExpansionError> {
+        let system_error =
+            |error: anyhow::Error| ProcMacroExpansionError::System(error.to_string());
+        let request = json::object! {
+            "lib" => dylib_path.to_string_lossy().as_ref(),
+            "env" => env
+                .into_iter()
+                .map(|(key, value)| json::array![key, value])
+                .collect::<Vec<_>>(),
+            "current_dir" => JsonValue::Null,
+            "macro_body" => encode_subtree(body),
+            "macro_name" => macro_name,
+            "attributes" => attributes.map(encode_subtree),
+            // Otherwise tokens that the macro creates would get the ID of whichever token the
+            // server assumes the macro was called from.
+            "has_global_spans" => json::object! {
+                "serialize" => true,
+                "def_site" => UNSPECIFIED_ID,
+                "call_site" => UNSPECIFIED_ID,
+                "mixed_site" => UNSPECIFIED_ID,
+            },
+        };
+        let response = self.request("ExpandMacro", request).map_err(system_error)?;
+        let expansion = result_from_json(response).map_err(ProcMacroExpansionError::Panic)?;
+        decode_subtree(&expansion).map_err(system_error)
+    }
+
+    /// Sends a request of type `kind` and returns the body of the response.
+    fn request(&self, kind: &str, body: JsonValue) -> Result<JsonValue> {
+        let mut request = JsonValue::new_object();
+        request[kind] = body;
+        let mut process = self.process.lock().unwrap();
+        writeln!(process.stdin, "{}", request.dump())
+            .and_then(|_| process.stdin.flush())
+            .context("Failed to send request to proc macro server")?;
+        let mut line = String::new();
+        if process.stdout.read_line(&mut line)? == 0 {
+            bail!("Proc macro server exited");
+        }
+        let mut response = json::parse(&line)?;
+        if !response.has_key(kind) {
+            bail!(
+                "Unexpected response from proc macro server: {}",
+                line.trim()
+            );
+        }
+        Ok(response.remove(kind))
+    }
+}
+
+impl Drop for ProcMacroServer {
+    fn drop(&mut self) {
+        if let Ok(process) = self.process.get_mut() {
+            let _ = process.child.kill();
+            let _ = process.child.wait();
+        }
+    }
+}
+
+/// Converts a serialized `Result` into a `Result` of its contents.
+fn result_from_json(mut result: JsonValue) -> Result<JsonValue, String> {
+    if result.has_key("Ok") {
+        Ok(result.remove("Ok"))
+    } else {
+        Err(result["Err"]
+            .as_str()
+            .map(str::to_owned)
+            .unwrap_or_else(|| result.dump()))
+    }
+}
+
+/// Literal kinds, as numbered by the proc macro server. For raw strings, the number of `#`s goes in
+/// the upper byte.
+mod literal_kind {
+    pub(super) const ERR: u32 = 0;
+    pub(super) const BYTE: u32 = 1;
+    pub(super) const CHAR: u32 = 2;
+    pub(super) const INTEGER: u32 = 3;
+    pub(super) const FLOAT: u32 = 4;
+    pub(super) const STR: u32 = 5;
+    pub(super) const STR_RAW: u32 = 6;
+    pub(super) const BYTE_STR: u32 = 7;
+    pub(super) const BYTE_STR_RAW: u32 = 8;
+    pub(super) const C_STR: u32 = 9;
+    pub(super) const C_STR_RAW: u32 = 10;
+}
+
+/// Splits the source text of a literal into its kind, the text between any quotes and its suffix,
+/// which is empty if it doesn't have one.
+fn split_literal(text: &str) -> (u32, &str, &str) {
+    use literal_kind::*;
+    fn quoted<'a>(kind: u32, text: &'a str, close: &str) -> Option<(u32, &'a str, &'a str)> {
+        let end = text.rfind(close)?;
+        Some((kind, &text[..end], &text[end + close.len()..]))
+    }
+    if let Some(rest) = text.strip_prefix("b'") {
+        if let Some(parts) = quoted(BYTE, rest, "'") {
+            return parts;
+        }
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        if let Some(parts) = quoted(CHAR, rest, "'") {
+            return parts;
+        }
+    }
+    for (prefix, kind, raw_kind) in [
+        ("", STR, STR_RAW),
+        ("b", BYTE_STR, BYTE_STR_RAW),
+        ("c", C_STR, C_STR_RAW),
+    ] {
+        let rest = match text.strip_prefix(prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        if let Some(rest) = rest.strip_prefix('"') {
+            if let Some(parts) = quoted(kind, rest, "\"") {
+                return parts;
+            }
+        }
+        if let Some(rest) = rest.strip_prefix('r') {
+            let hashes = rest.len() - rest.trim_start_matches('#').len();
+            if let Some(rest) = rest[hashes..].strip_prefix('"') {
+                let close = format!("\"{}", "#".repeat(hashes));
+                if let Some(parts) = quoted(raw_kind | ((hashes as u32) << 8), rest, &close) {
+                    return parts;
+                }
+            }
+        }
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        // Suffixes of numbers start with `i`, `u` or `f`, except that in hexadecimal, `f` is a
+        // digit.
+        let suffix_start = if let Some(digits) = text.strip_prefix("0x") {
+            digits.find(['i', 'u']).map(|index| index + 2)
+        } else {
+            text.find(['i', 'u', 'f'])
+        }
+        .unwrap_or(text.len());
+        let (number, suffix) = text.split_at(suffix_start);
+        let is_float = suffix.starts_with('f')
+            || (!number.starts_with("0x") && number.contains(['.', 'e', 'E']));
+        return (if is_float { FLOAT } else { INTEGER }, number, suffix);
+    }
+    (ERR, text, "")
+}
+
+/// The inverse of `split_literal`.
+fn join_literal(kind: u32, symbol: &str, suffix: &str) -> String {
+    use literal_kind::*;
+    let hashes = "#".repeat((kind >> 8) as usize);
+    let literal = match kind & 0xff {
+        BYTE => format!("b'{}'", symbol),
+        CHAR => format!("'{}'", symbol),
+        STR => format!("\"{}\"", symbol),
+        STR_RAW => format!("r{0}\"{1}\"{0}", hashes, symbol),
+        BYTE_STR => format!("b\"{}\"", symbol),
+        BYTE_STR_RAW => format!("br{0}\"{1}\"{0}", hashes, symbol),
+        C_STR => format!("c\"{}\"", symbol),
+        C_STR_RAW => format!("cr{0}\"{1}\"{0}", hashes, symbol),
+        _ => symbol.to_owned(),
+    };
+    literal + suffix
+}
+
+/// Converts a token tree into the flat form that the proc macro server expects. Subtrees are
+/// numbered breadth first. Each has an ID for its opening and closing delimiters, its delimiter
+/// kind and a range of `token_tree`. Each entry of `token_tree` is the index of a subtree or leaf,
+/// shifted left by two bits to make room for which of those it is.
+fn encode_subtree(root: &tt::Subtree) -> JsonValue {
+    #[derive(Default)]
+    struct Writer<'a> {
+        queue: VecDeque<(usize, &'a tt::Subtree)>,
+        text_indexes: HashMap<String, u32>,
+        subtree: Vec<u32>,
+        literal: Vec<u32>,
+        punct: Vec<u32>,
+        ident: Vec<u32>,
+        token_tree: Vec<u32>,
+        text: Vec<String>,
+    }
+
+    impl<'a> Writer<'a> {
+        fn enqueue(&mut self, subtree: &'a tt::Subtree) -> u32 {
+            let index = self.subtree.len() / 5;
+            let (id, kind) = match subtree.delimiter {
+                None => (UNSPECIFIED_ID, 0),
+                Some(tt::Delimiter { id, kind }) => (
+                    id.0,
+                    match kind {
+                        tt::DelimiterKind::Parenthesis => 1,
+                        tt::DelimiterKind::Brace => 2,
+                        tt::DelimiterKind::Bracket => 3,
+                    },
+                ),
+            };
+            // The range of `token_tree` gets filled in when we dequeue the subtree.
+            self.subtree.extend([id, id, kind, 0, 0]);
+            self.queue.push_back((index, subtree));
+            index as u32
+        }
+
+        fn intern(&mut self, text: &str) -> u32 {
+            if let Some(index) = self.text_indexes.get(text) {
+                return *index;
+            }
+            let index = self.text.len() as u32;
+            self.text.push(text.to_owned());
+            self.text_indexes.insert(text.to_owned(), index);
+            index
+        }
+
+        fn write_leaf(&mut self, leaf: &tt::Leaf) -> u32 {
+            let (index, tag) = match leaf {
+                tt::Leaf::Literal(literal) => {
+                    let (kind, symbol, suffix) = split_literal(&literal.text);
+                    let symbol = self.intern(symbol);
+                    let suffix = if suffix.is_empty() {
+                        UNSPECIFIED_ID
+                    } else {
+                        self.intern(suffix)
+                    };
+                    self.literal.extend([literal.id.0, symbol, kind, suffix]);
+                    (self.literal.len() / 4 - 1, 1)
+                }
+                tt::Leaf::Punct(punct) => {
+                    let spacing = match punct.spacing {
+                        tt::Spacing::Alone => 0,
+                        tt::Spacing::Joint => 1,
+                    };
+                    self.punct.extend([punct.id.0, punct.char as u32, spacing]);
+                    (self.punct.len() / 3 - 1, 2)
+                }
+                tt::Leaf::Ident(ident) => {
+                    let (text, is_raw) = match ident.text.strip_prefix("r#") {
+                        Some(text) => (text, 1),
+                        None => (ident.text.as_str(), 0),
+                    };
+                    let text = self.intern(text);
+                    self.ident.extend([ident.id.0, text, is_raw]);
+                    (self.ident.len() / 3 - 1, 3)
+                }
+            };
+            ((index as u32) << 2) | tag
+        }
+    }
+
+    let mut writer = Writer::default();
+    writer.enqueue(root);
+    while let Some((index, subtree)) = writer.queue.pop_front() {
+        let first = writer.token_tree.len() as u32;
+        for token in &subtree.token_trees {
+            let entry = match token {
+                tt::TokenTree::Subtree(subtree) => writer.enqueue(subtree) << 2,
+                tt::TokenTree::Leaf(leaf) => writer.write_leaf(leaf),
+            };
+            writer.token_tree.push(entry);
+        }
+        writer.subtree[index * 5 + 3] = first;
+        writer.subtree[index * 5 + 4] = writer.token_tree.len() as u32;
+    }
+    json::object! {
+        "subtree" => writer.subtree,
+        "literal" => writer.literal,
+        "punct" => writer.punct,
+        "ident" => writer.ident,
+        "token_tree" => writer.token_tree,
+        "text" => writer.text,
+    }
+}
+
+/// The inverse of `encode_subtree`.
+fn decode_subtree(flat: &JsonValue) -> Result<tt::Subtree> {
+    fn leaf(values: &[u32], index: usize, count: usize) -> Result<&[u32]> {
+        values
+            .get(index * count..(index + 1) * count)
+            .ok_or_else(|| anyhow!("Token tree refers to missing leaf {}", index))
+    }
+    let numbers = |key: &str| -> Result<Vec<u32>> {
+        flat[key]
+            .members()
+            .map(|value| {
+                value
+                    .as_u32()
+                    .ok_or_else(|| anyhow!("Invalid `{}` in token tree: {}", key, value))
+            })
+            .collect()
+    };
+    let subtree = numbers("subtree")?;
+    let literal = numbers("literal")?;
+    let punct = numbers("punct")?;
+    let ident = numbers("ident")?;
+    let token_tree = numbers("token_tree")?;
+    let text: Vec<&str> = flat["text"]
+        .members()
+        .filter_map(JsonValue::as_str)
+        .collect();
+    let text = |index: u32| {
+        text.get(index as usize)
+            .copied()
+            .ok_or_else(|| anyhow!("Token tree refers to missing text {}", index))
+    };
+
+    // Subtrees only contain subtrees that come after them, so by going backwards, we always have
+    // the contents of a subtree by the time we get to it.
+    let mut subtrees: Vec<Option<tt::Subtree>> = vec![None; subtree.len() / 5];
+    for (index, repr) in subtree.chunks_exact(5).enumerate().rev() {
+        let id = tt::TokenId(repr[0]);
+        let delimiter = match repr[2] {
+            0 => None,
+            1 => Some(tt::DelimiterKind::Parenthesis),
+            2 => Some(tt::DelimiterKind::Brace),
+            3 => Some(tt::DelimiterKind::Bracket),
+            kind => bail!("Unrecognised delimiter kind {}", kind),
+        }
+        .map(|kind| tt::Delimiter { id, kind });
+        let entries = token_tree
+            .get(repr[3] as usize..repr[4] as usize)
+            .ok_or_else(|| anyhow!("Subtree {} has an invalid range", index))?;
+        let mut token_trees = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let child = (entry >> 2) as usize;
+            token_trees.push(match entry & 3 {
+                0 => tt::TokenTree::Subtree(
+                    subtrees
+                        .get_mut(child)
+                        .and_then(Option::take)
+                        .ok_or_else(|| anyhow!("Token tree refers to missing subtree {}", child))?,
+                ),
+                1 => {
+                    let values = leaf(&literal, child, 4)?;
+                    let suffix = if values[3] == UNSPECIFIED_ID {
+                        ""
+                    } else {
+                        text(values[3])?
+                    };
+                    tt::Leaf::Literal(tt::Literal {
+                        text: join_literal(values[2], text(values[1])?, suffix).into(),
+                        id: tt::TokenId(values[0]),
+                    })
+                    .into()
+                }
+                2 => {
+                    let values = leaf(&punct, child, 3)?;
+                    tt::Leaf::Punct(tt::Punct {
+                        char: char::from_u32(values[1])
+                            .ok_or_else(|| anyhow!("Invalid punctuation {}", values[1]))?,
+                        // The server also has spacing 2, which is joint to something that isn't
+                        // punctuation, such as the `'` of a lifetime.
+                        spacing: if values[2] == 0 {
+                            tt::Spacing::Alone
+                        } else {
+                            tt::Spacing::Joint
+                        },
+                        id: tt::TokenId(values[0]),
+                    })
+                    .into()
+                }
+                _ => {
+                    let values = leaf(&ident, child, 3)?;
+                    let text = text(values[1])?;
+                    tt::Leaf::Ident(tt::Ident {
+                        text: if values[2] == 0 {
+                            text.into()
+                        } else {
+                            format!("r#{}", text).into()
+                        },
+                        id: tt::TokenId(values[0]),
+                    })
+                    .into()
+                }
+            });
+        }
+        subtrees[index] = Some(tt::Subtree {
+            delimiter,
+            token_trees,
+        });
+    }
+    subtrees
+        .first_mut()
+        .and_then(Option::take)
+        .ok_or_else(|| anyhow!("Token tree is empty"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_subtree;
+    use super::encode_subtree;
+    use super::join_literal;
+    use super::split_literal;
+    use ra_ap_tt as tt;
+
+    #[test]
+    fn literals_round_trip() {
+        for (literal, symbol, suffix) in [
+            ("42", "42", ""),
+            ("42u8", "42", "u8"),
+            ("0x1f", "0x1f", ""),
+            ("0x1fi64", "0x1f", "i64"),
+            ("1.5", "1.5", ""),
+            ("1e10f32", "1e10", "f32"),
+            ("'a'", "a", ""),
+            ("b'\\n'", "\\n", ""),
+            ("\"hello\"", "hello", ""),
+            ("\"a\\\"b\"", "a\\\"b", ""),
+            ("r#\"a\"b\"#", "a\"b", ""),
+            ("b\"bytes\"", "bytes", ""),
+            ("br\"raw\"", "raw", ""),
+            ("c\"text\"", "text", ""),
+        ] {
+            let (kind, split_symbol, split_suffix) = split_literal(literal);
+            assert_eq!(
+                (split_symbol, split_suffix),
+                (symbol, suffix),
+                "{}",
+                literal
+            );
+            assert_eq!(join_literal(kind, symbol, suffix), literal);
+        }
+    }
+
+    #[test]
+    fn token_trees_round_trip() {
+        let id = tt::TokenId;
+        let leaf = |leaf: tt::Leaf| tt::TokenTree::Leaf(leaf);
+        let ident = |text: &str, n| {
+            leaf(tt::Leaf::Ident(tt::Ident {
+                text: text.into(),
+                id: id(n),
+            }))
+        };
+        // struct r#Foo { x: [u8; 2] }
+        let subtree = tt::Subtree {
+            delimiter: None,
+            token_trees: vec![
+                ident("struct", 0),
+                ident("r#Foo", 1),
+                tt::TokenTree::Subtree(tt::Subtree {
+                    delimiter: Some(tt::Delimiter {
+                        id: id(2),
+                        kind: tt::DelimiterKind::Brace,
+                    }),
+                    token_trees: vec![
+                        ident("x", 3),
+                        leaf(tt::Leaf::Punct(tt::Punct {
+                            char: ':',
+                            spacing: tt::Spacing::Alone,
+                            id: id(4),
+                        })),
+                        tt::TokenTree::Subtree(tt::Subtree {
+                            delimiter: Some(tt::Delimiter {
+                                id: id(5),
+                                kind: tt::DelimiterKind::Bracket,
+                            }),
+                            token_trees: vec![
+                                ident("u8", 6),
+                                leaf(tt::Leaf::Punct(tt::Punct {
+                                    char: ';',
+                                    spacing: tt::Spacing::Alone,
+                                    id: id(7),
+                                })),
+                                leaf(tt::Leaf::Literal(tt::Literal {
+                                    text: "2".into(),
+                                    id: id(8),
+                                })),
+                            ],
+                        }),
+                    ],
+                }),
+            ],
+        };
+        assert_eq!(decode_subtree(&encode_subtree(&subtree)).unwrap(), subtree);
+    }
+}
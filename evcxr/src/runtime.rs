@@ -23,6 +23,7 @@ use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
 pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+pub(crate) const EVCXR_IS_RUSTC_WRAPPER_VAR: &str = "EVCXR_IS_RUSTC_WRAPPER";
 
 /// Binaries can call this just after staring. If we detect that we're actually
 /// running as a subprocess, control will not return.
@@ -30,6 +31,43 @@ pub fn runtime_hook() {
     if std::env::var(EVCXR_IS_RUNTIME_VAR).is_ok() {
         Runtime::new().run_loop();
     }
+    if std::env::var(EVCXR_IS_RUSTC_WRAPPER_VAR).is_ok() {
+        run_rustc_wrapper();
+    }
+}
+
+/// Runs as a wrapper around rustc for the `cargo check` that builds our dependencies' build scripts
+/// and proc macros for rust-analyzer. Crates that are only being checked are skipped, since
+/// rust-analyzer doesn't need them and they'd take much longer. This is the same thing that
+/// rust-analyzer itself does.
+fn run_rustc_wrapper() -> ! {
+    let mut args = std::env::args_os().skip(1);
+    let rustc = match args.next() {
+        Some(rustc) => rustc,
+        None => {
+            eprintln!("Expected to be passed the path to rustc");
+            std::process::exit(1);
+        }
+    };
+    let args: Vec<_> = args.collect();
+    // When checking, cargo asks for metadata, but not a linked crate. Cargo only sets
+    // CARGO_CFG_TARGET_ARCH when running build scripts, which may run rustc via the wrapper for
+    // their own reasons, so in that case we always run it.
+    let only_checking = std::env::var_os("CARGO_CFG_TARGET_ARCH").is_none()
+        && args.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg.starts_with("--emit=") && arg.contains("metadata") && !arg.contains("link")
+        });
+    if only_checking {
+        std::process::exit(0);
+    }
+    match std::process::Command::new(rustc).args(args).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(error) => {
+            eprintln!("Failed to run rustc: {}", error);
+            std::process::exit(1);
+        }
+    }
 }
 
 struct Runtime {
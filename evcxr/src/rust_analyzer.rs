@@ -12,22 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::eval_context::Config;
+use crate::proc_macro_server::ProcMacroServer;
+use crate::runtime::EVCXR_IS_RUSTC_WRAPPER_VAR;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
+use ra_ap_base_db::Env;
 use ra_ap_base_db::FileId;
+use ra_ap_base_db::ProcMacro;
+use ra_ap_base_db::ProcMacroExpander;
+use ra_ap_base_db::ProcMacroExpansionError;
+use ra_ap_base_db::ProcMacroLoadResult;
 use ra_ap_base_db::SourceRoot;
 use ra_ap_hir as ra_hir;
 use ra_ap_ide as ra_ide;
 use ra_ap_ide_db::imports::insert_use::ImportGranularity;
 use ra_ap_ide_db::imports::insert_use::InsertUseConfig;
 use ra_ap_ide_db::SnippetCap;
+use ra_ap_paths::AbsPath;
 use ra_ap_paths::AbsPathBuf;
 use ra_ap_project_model::CargoConfig;
 use ra_ap_project_model::ProjectManifest;
 use ra_ap_project_model::ProjectWorkspace;
+use ra_ap_project_model::WorkspaceBuildScripts;
 use ra_ap_syntax::ast::AstNode;
 use ra_ap_syntax::ast::{self};
 use ra_ap_vfs as ra_vfs;
@@ -35,7 +45,9 @@ use ra_ap_vfs_notify as vfs_notify;
 use ra_ide::CallableSnippets;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::ops::Range;
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::Arc;
 
@@ -47,9 +59,61 @@ pub(crate) struct RustAnalyzer {
     loader: vfs_notify::NotifyHandle,
     message_receiver: mpsc::Receiver<ra_vfs::loader::Message>,
     last_cargo_toml: Option<Vec<u8>>,
-    source_file: AbsPathBuf,
-    source_file_id: FileId,
-    current_source: Arc<String>,
+    lib_file: AnalyzedFile,
+    cell_file: AnalyzedFile,
+    /// A process that expands proc macros from our dependencies, or why we couldn't start it. None
+    /// if we haven't tried to start it yet. We only try once per toolchain, since if it fails, it'll
+    /// most likely fail again.
+    proc_macro_server: Option<Result<Arc<ProcMacroServer>, String>>,
+    /// The workspace loaded from our Cargo.toml, if we've loaded it.
+    workspace: Option<ProjectWorkspace>,
+    /// Receives the output of running build scripts for `workspace` in the background.
+    pending_build_scripts: Option<mpsc::Receiver<WorkspaceBuildScripts>>,
+    /// The session's configuration, which determines how we run cargo and rustc.
+    config: Config,
+    /// A binary that calls `runtime_hook`, which cargo can run as a rustc wrapper so as to only
+    /// build what we need for analysis.
+    rustc_wrapper: Option<PathBuf>,
+}
+
+/// The name of the module containing the code being analyzed.
+pub(crate) const CELL_MODULE: &str = "evcxr_cell";
+
+/// One of the files that we give to rust-analyzer. Session items go in the crate root, `Lib`, while
+/// the code currently being analyzed goes in a wrapper function in the module `Cell`. Since items
+/// change much less often than the code being analyzed, this lets rust-analyzer reuse most of what
+/// it has computed between analyses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AnalysisFile {
+    Lib,
+    Cell,
+}
+
+/// A byte offset within one of the files that we give to rust-analyzer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) file: AnalysisFile,
+    pub(crate) offset: usize,
+}
+
+struct AnalyzedFile {
+    path: AbsPathBuf,
+    file_id: FileId,
+    contents: Arc<String>,
+}
+
+impl AnalyzedFile {
+    fn new(path: AbsPathBuf, vfs: &mut ra_vfs::Vfs) -> AnalyzedFile {
+        // Pre-allocate an ID for the file.
+        let vfs_path: ra_vfs::VfsPath = path.clone().into();
+        vfs.set_file_contents(vfs_path.clone(), Some(vec![]));
+        let file_id = vfs.file_id(&vfs_path).unwrap();
+        AnalyzedFile {
+            path,
+            file_id,
+            contents: Arc::new(String::new()),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -68,23 +132,21 @@ pub(crate) struct VariableInfo {
 }
 
 impl RustAnalyzer {
-    pub(crate) fn new(root_directory: &Path) -> Result<RustAnalyzer> {
+    pub(crate) fn new(root_directory: &Path, rustc_wrapper: Option<&Path>) -> Result<RustAnalyzer> {
         use ra_vfs::loader::Handle;
         let (message_sender, message_receiver) = std::sync::mpsc::channel();
         let mut vfs = ra_vfs::Vfs::default();
+        let config = Config::new(root_directory.to_owned());
         let root_directory = AbsPathBuf::try_from(root_directory.to_owned())
             .map_err(|path| anyhow!("Evcxr tmpdir is not absolute: '{:?}'", path))?;
-        let source_file = root_directory.join("src/lib.rs");
-        // We need to write the file to the filesystem even though we subsequently set the file
+        // We need to write our files to the filesystem even though we subsequently set the file
         // contents via the vfs and change.change_file. This is because the loader checks for the
         // files existence when determining the crate structure.
         let src_dir = root_directory.join("src");
         std::fs::create_dir_all(&src_dir)
             .with_context(|| format!("Failed to create directory `{:?}`", src_dir))?;
-        // Pre-allocate an ID for our main source file.
-        let vfs_source_file: ra_vfs::VfsPath = source_file.clone().into();
-        vfs.set_file_contents(vfs_source_file.clone(), Some(vec![]));
-        let source_file_id = vfs.file_id(&vfs_source_file).unwrap();
+        let lib_file = AnalyzedFile::new(src_dir.join("lib.rs"), &mut vfs);
+        let cell_file = AnalyzedFile::new(src_dir.join(format!("{}.rs", CELL_MODULE)), &mut vfs);
         Ok(RustAnalyzer {
             with_sysroot: true,
             root_directory,
@@ -95,23 +157,34 @@ impl RustAnalyzer {
             })),
             message_receiver,
             last_cargo_toml: None,
-            source_file,
-            source_file_id,
-            current_source: Arc::new(String::new()),
+            lib_file,
+            cell_file,
+            proc_macro_server: None,
+            workspace: None,
+            pending_build_scripts: None,
+            config,
+            rustc_wrapper: rustc_wrapper.map(Path::to_owned),
         })
     }
 
-    pub(crate) fn set_source(&mut self, source: String) -> Result<()> {
-        self.current_source = Arc::new(source);
-        let mut change = ra_ide::Change::new();
+    /// Sets the session's configuration. We run cargo and rustc the same way that it says to build
+    /// the user's code, so that proc macros are built by the same compiler as the proc macro server
+    /// that expands them.
+    pub(crate) fn set_config(&mut self, config: &Config) {
+        if config.toolchain != self.config.toolchain {
+            // Our dependencies' proc macros need to be rebuilt with the new toolchain, and expanded
+            // by its proc macro server.
+            self.last_cargo_toml = None;
+            self.proc_macro_server = None;
+        }
+        self.config = config.clone();
+    }
 
-        std::fs::write(self.source_file.as_path(), &*self.current_source)
-            .with_context(|| format!("Failed to write {:?}", self.source_file))?;
-        self.vfs.set_file_contents(
-            self.source_file.clone().into(),
-            Some(self.current_source.bytes().collect()),
-        );
-        change.change_file(self.source_file_id, Some(Arc::clone(&self.current_source)));
+    /// Sets the contents of the crate root, which should declare `mod evcxr_cell;`, and of that
+    /// module. Files that haven't changed since the last call are left alone, so that rust-analyzer
+    /// can keep what it has already computed for them.
+    pub(crate) fn set_source(&mut self, lib: String, cell: String) -> Result<()> {
+        let mut change = ra_ide::Change::new();
 
         // Check to see if we haven't yet loaded Cargo.toml, or if it's changed since we read it.
         let cargo_toml = Some(std::fs::read(self.cargo_toml_filename()).with_context(|| {
@@ -120,22 +193,68 @@ impl RustAnalyzer {
                 self.cargo_toml_filename()
             )
         })?);
-        if cargo_toml != self.last_cargo_toml {
+        let reload_cargo_toml = cargo_toml != self.last_cargo_toml;
+
+        let mut changed = false;
+        for (file, contents) in [(AnalysisFile::Lib, lib), (AnalysisFile::Cell, cell)] {
+            let analyzed_file = match file {
+                AnalysisFile::Lib => &mut self.lib_file,
+                AnalysisFile::Cell => &mut self.cell_file,
+            };
+            // If we're reloading Cargo.toml, the loader will read our files from disk, so we need
+            // to write them even if they're unchanged, since they may have been overwritten.
+            if *analyzed_file.contents == contents && !reload_cargo_toml {
+                continue;
+            }
+            changed = true;
+            analyzed_file.contents = Arc::new(contents);
+            std::fs::write(analyzed_file.path.as_path(), &*analyzed_file.contents)
+                .with_context(|| format!("Failed to write {:?}", analyzed_file.path))?;
+            self.vfs.set_file_contents(
+                analyzed_file.path.clone().into(),
+                Some(analyzed_file.contents.bytes().collect()),
+            );
+            change.change_file(
+                analyzed_file.file_id,
+                Some(Arc::clone(&analyzed_file.contents)),
+            );
+        }
+
+        if reload_cargo_toml {
             self.load_cargo_toml(&mut change)?;
             self.last_cargo_toml = cargo_toml;
+        } else if self.receive_build_scripts(&mut change, false) {
+            changed = true;
         }
 
-        self.analysis_host.apply_change(change);
+        if changed {
+            self.analysis_host.apply_change(change);
+        }
         Ok(())
     }
 
-    /// Returns top-level variable names and their types in the specified function.
+    fn analyzed_file(&self, file: AnalysisFile) -> &AnalyzedFile {
+        match file {
+            AnalysisFile::Lib => &self.lib_file,
+            AnalysisFile::Cell => &self.cell_file,
+        }
+    }
+
+    fn file_position(&self, position: Position) -> ra_ide::FilePosition {
+        ra_ide::FilePosition {
+            file_id: self.analyzed_file(position.file).file_id,
+            offset: (position.offset as u32).into(),
+        }
+    }
+
+    /// Returns top-level variable names and their types in the specified function, which should be
+    /// in the cell module.
     pub(crate) fn top_level_variables(&self, function_name: &str) -> HashMap<String, VariableInfo> {
         use ra_ap_syntax::ast::HasModuleItem;
         use ra_ap_syntax::ast::HasName;
         let mut result = HashMap::new();
         let sema = ra_ide::Semantics::new(self.analysis_host.raw_database());
-        let source_file = sema.parse(self.source_file_id);
+        let source_file = sema.parse(self.cell_file.file_id);
         for item in source_file.items() {
             if let ast::Item::Fn(function) = item {
                 if function
@@ -189,13 +308,84 @@ impl RustAnalyzer {
         result
     }
 
+    /// Waits for build scripts that are running in the background, if any, to finish, so that
+    /// analysis can see proc macros from our dependencies. Returns whether analysis changed as a
+    /// result.
+    pub(crate) fn wait_for_build_scripts(&mut self) -> bool {
+        let mut change = ra_ide::Change::new();
+        if !self.receive_build_scripts(&mut change, true) {
+            return false;
+        }
+        self.analysis_host.apply_change(change);
+        true
+    }
+
+    /// Updates the crate graph in `change` with the output of build scripts that were running in
+    /// the background. If `wait` is false and they haven't finished yet, does nothing. Returns
+    /// whether the crate graph was updated.
+    fn receive_build_scripts(&mut self, change: &mut ra_ide::Change, wait: bool) -> bool {
+        let build_scripts = match &self.pending_build_scripts {
+            Some(receiver) if wait => receiver.recv().ok(),
+            Some(receiver) => match receiver.try_recv() {
+                Err(mpsc::TryRecvError::Empty) => return false,
+                result => result.ok(),
+            },
+            None => return false,
+        };
+        self.pending_build_scripts = None;
+        match (build_scripts, &mut self.workspace) {
+            (Some(build_scripts), Some(workspace)) => {
+                workspace.set_build_scripts(build_scripts);
+                self.set_crate_graph(change);
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn load_cargo_toml(&mut self, change: &mut ra_ide::Change) -> Result<()> {
         let manifest = ProjectManifest::from_manifest_file(self.cargo_toml_filename())?;
-        let config = CargoConfig {
+        let mut config = CargoConfig {
             no_sysroot: !self.with_sysroot,
             ..CargoConfig::default()
         };
+        if self.config.offline_mode {
+            // rust-analyzer doesn't give us a way to pass --offline when it runs `cargo metadata`,
+            // so we run it first ourselves. This brings Cargo.lock up to date, after which cargo
+            // doesn't need the network when rust-analyzer runs it.
+            let output = self
+                .config
+                .cargo_command("metadata")
+                .args(["--format-version", "1"])
+                .output()
+                .context("Failed to run cargo metadata")?;
+            if !output.status.success() {
+                bail!(
+                    "cargo metadata failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        config.run_build_script_command = Some(self.build_script_command());
         let workspace = ProjectWorkspace::load(manifest, &config, &|_| {})?;
+        // Results from running build scripts for a previous version of Cargo.toml are of no use.
+        self.pending_build_scripts = None;
+        if self.with_sysroot {
+            self.start_proc_macro_server();
+            // Running build scripts is what builds our dependencies' proc macros, so we need to do
+            // it before we can expand them. It can take a while, so we do it in the background and
+            // pick up the result in a later call to set_source. If it fails, we carry on without
+            // expanding them.
+            let (sender, receiver) = mpsc::channel();
+            let background_workspace = workspace.clone();
+            std::thread::spawn(move || {
+                if let Ok(build_scripts) = background_workspace.run_build_scripts(&config, &|_| {})
+                {
+                    let _ = sender.send(build_scripts);
+                }
+            });
+            self.pending_build_scripts = Some(receiver);
+        }
         let load = workspace
             .to_roots()
             .iter()
@@ -207,6 +397,7 @@ impl RustAnalyzer {
                 })
             })
             .collect();
+        self.workspace = Some(workspace);
         // Note, set_config is what triggers loading and calling the callback that we registered when we created self.loader.
         use ra_vfs::loader::Handle;
         self.loader.set_config(ra_vfs::loader::Config {
@@ -253,25 +444,81 @@ impl RustAnalyzer {
                 .map(SourceRoot::new_local)
                 .collect(),
         );
-        change.set_crate_graph(
-            workspace.to_crate_graph(&mut |_, _| Ok(Vec::new()), &mut |path| {
-                self.vfs.file_id(&path.to_path_buf().into())
-            }),
-        );
+        self.set_crate_graph(change);
         Ok(())
     }
 
+    /// Returns the command that rust-analyzer should run to build our dependencies' build scripts
+    /// and proc macros.
+    fn build_script_command(&self) -> Vec<String> {
+        let mut command = self.config.cargo_command("check");
+        // Building for analysis happens while we might be compiling, so we give it its own target
+        // directory. Otherwise cargo would make each wait for the other.
+        command
+            .args([
+                "--quiet",
+                "--workspace",
+                "--message-format=json",
+                "--target-dir",
+            ])
+            .arg(self.root_directory.join("target").as_os_str())
+            .arg("--config")
+            .arg(format!("build.rustc={:?}", self.config.rustc_path));
+        if let Some(rustc_wrapper) = &self.rustc_wrapper {
+            // Our wrapper skips checking crates, so that only build scripts and proc macros get
+            // built. We can't set environment variables for the command, so we have cargo set the
+            // one that tells our binary to act as the wrapper.
+            command
+                .arg("--config")
+                .arg(format!("build.rustc-wrapper={:?}", rustc_wrapper))
+                .arg("--config")
+                .arg(format!("env.{}=\"1\"", EVCXR_IS_RUSTC_WRAPPER_VAR));
+        }
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn start_proc_macro_server(&mut self) {
+        if self.proc_macro_server.is_some() {
+            return;
+        }
+        let server = sysroot_proc_macro_server(&self.config)
+            .and_then(ProcMacroServer::spawn)
+            .map(Arc::new)
+            .map_err(|error| error.to_string());
+        if server.is_ok() {
+            use ra_hir::db::DefDatabase;
+            self.analysis_host
+                .raw_database_mut()
+                .set_enable_proc_attr_macros(true);
+        }
+        self.proc_macro_server = Some(server);
+    }
+
+    fn set_crate_graph(&self, change: &mut ra_ide::Change) {
+        if let Some(workspace) = &self.workspace {
+            change.set_crate_graph(workspace.to_crate_graph(
+                &mut |_, path| load_proc_macros(&self.proc_macro_server, path),
+                &mut |path| self.vfs.file_id(&path.to_path_buf().into()),
+            ));
+        }
+    }
+
     fn cargo_toml_filename(&self) -> AbsPathBuf {
         self.root_directory.join("Cargo.toml")
     }
 
-    pub(crate) fn completions(&self, position: usize) -> Result<Completions> {
+    /// Returns completions at `position`. The offsets in the returned completions are within the
+    /// same file as `position`.
+    pub(crate) fn completions(&self, position: Position) -> Result<Completions> {
         let mut completions = Vec::new();
         let mut range = None;
         let config = ra_ide::CompletionConfig {
             enable_postfix_completions: true,
             snippet_cap: SnippetCap::new(true),
-            enable_imports_on_the_fly: false,
+            enable_imports_on_the_fly: true,
             enable_self_on_the_fly: true,
             enable_private_editable: true,
             snippets: vec![],
@@ -284,14 +531,11 @@ impl RustAnalyzer {
             },
             callable: Some(CallableSnippets::FillArguments),
         };
-        if let Ok(Some(completion_items)) = self.analysis_host.analysis().completions(
-            &config,
-            ra_ide::FilePosition {
-                file_id: self.source_file_id,
-                offset: (position as u32).into(),
-            },
-            None,
-        ) {
+        if let Ok(Some(completion_items)) =
+            self.analysis_host
+                .analysis()
+                .completions(&config, self.file_position(position), None)
+        {
             for item in completion_items {
                 use regex::Regex;
                 static ARG_PLACEHOLDER: OnceCell<Regex> = OnceCell::new();
@@ -299,7 +543,7 @@ impl RustAnalyzer {
                     ARG_PLACEHOLDER.get_or_init(|| Regex::new("\\$\\{[0-9]+:([^}]*)\\}").unwrap());
                 let mut indels = item.text_edit().iter();
                 if let Some(indel) = indels.next() {
-                    let text_to_delete = &self.current_source[indel.delete];
+                    let text_to_delete = &self.analyzed_file(position.file).contents[indel.delete];
                     // Rust analyzer returns all available methods/fields etc. It's up to us to
                     // decide how what we filter and what we keep.
                     if !item.lookup().starts_with(text_to_delete) {
@@ -309,6 +553,14 @@ impl RustAnalyzer {
                         code: arg_placeholder
                             .replace_all(&indel.insert, "$1")
                             .replace("$0", ""),
+                        kind: CompletionKind::from_item_kind(item.kind()),
+                        detail: item.detail().map(str::to_owned),
+                        documentation: item.documentation().map(|doc| doc.as_str().to_owned()),
+                        imports: item
+                            .imports_to_add()
+                            .iter()
+                            .map(|import| format!("use {};", import.import_path))
+                            .collect(),
                     });
                     if let Some(previous_range) = range.as_ref() {
                         if *previous_range != indel.delete {
@@ -325,12 +577,214 @@ impl RustAnalyzer {
         }
         Ok(Completions {
             completions,
-            start_offset: range.map(|range| range.start().into()).unwrap_or(position),
-            end_offset: range.map(|range| range.end().into()).unwrap_or(position),
+            start_offset: range
+                .map(|range| range.start().into())
+                .unwrap_or(position.offset),
+            end_offset: range
+                .map(|range| range.end().into())
+                .unwrap_or(position.offset),
+        })
+    }
+
+    /// Returns information about the item at `position`, as would be shown when hovering over it
+    /// in an editor. The offsets in the result are within the same file as `position`.
+    pub(crate) fn hover(&self, position: Position) -> Result<Option<Hover>> {
+        let config = ra_ide::HoverConfig {
+            links_in_hover: false,
+            documentation: Some(ra_ide::HoverDocFormat::Markdown),
+            keywords: true,
+        };
+        let range_info = match self.analysis_host.analysis().hover(
+            &config,
+            ra_ide::FileRange {
+                file_id: self.analyzed_file(position.file).file_id,
+                range: ra_ide::TextRange::empty((position.offset as u32).into()),
+            },
+        ) {
+            Ok(Some(range_info)) => range_info,
+            _ => return Ok(None),
+        };
+        Ok(Some(Hover {
+            markdown: range_info.info.markup.to_string(),
+            start_offset: range_info.range.start().into(),
+            end_offset: range_info.range.end().into(),
+        }))
+    }
+
+    /// Returns information about the function or method call whose arguments contain `position`.
+    pub(crate) fn signature_help(&self, position: Position) -> Result<Option<SignatureHelp>> {
+        let help = match self
+            .analysis_host
+            .analysis()
+            .signature_help(self.file_position(position))
+        {
+            Ok(Some(help)) => help,
+            _ => return Ok(None),
+        };
+        Ok(Some(SignatureHelp {
+            parameter_ranges: help
+                .parameter_ranges()
+                .iter()
+                .map(|range| range.start().into()..range.end().into())
+                .collect(),
+            active_parameter: help.active_parameter,
+            documentation: help.doc,
+            signature: help.signature,
+        }))
+    }
+
+    /// Returns what the macro call at `position` expands to.
+    pub(crate) fn expand_macro(&self, position: Position) -> Result<Option<MacroExpansion>> {
+        match self
+            .analysis_host
+            .analysis()
+            .expand_macro(self.file_position(position))
+        {
+            Ok(Some(expanded)) => Ok(Some(MacroExpansion {
+                name: expanded.name,
+                expansion: expanded.expansion,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns problems with the current source of `file` that rust-analyzer can find without
+    /// running cargo.
+    pub(crate) fn diagnostics(&self, file: AnalysisFile) -> Result<Vec<Diagnostic>> {
+        // Proc macro support is left disabled in the config, so that any proc macros that we
+        // couldn't expand are only reported as weak warnings, which we ignore.
+        let config = ra_ide::DiagnosticsConfig {
+            disable_experimental: true,
+            ..ra_ide::DiagnosticsConfig::test_sample()
+        };
+        let diagnostics = match self.analysis_host.analysis().diagnostics(
+            &config,
+            ra_ide::AssistResolveStrategy::None,
+            self.analyzed_file(file).file_id,
+        ) {
+            Ok(diagnostics) => diagnostics,
+            Err(_) => bail!("Diagnostics were cancelled"),
+        };
+        Ok(diagnostics
+            .into_iter()
+            // Experimental diagnostics are off by default in editors because they're prone to
+            // false positives. Weak warnings are mostly style suggestions, which we leave to
+            // cargo check.
+            .filter(|diagnostic| {
+                !diagnostic.experimental
+                    && !matches!(diagnostic.severity, ra_ide::Severity::WeakWarning)
+            })
+            .map(|diagnostic| Diagnostic {
+                message: diagnostic.message,
+                level: if matches!(diagnostic.severity, ra_ide::Severity::Error) {
+                    "error"
+                } else {
+                    "warning"
+                },
+                code: diagnostic.code.as_str().to_owned(),
+                start_offset: diagnostic.range.start().into(),
+                end_offset: diagnostic.range.end().into(),
+            })
+            .collect())
+    }
+}
+
+/// Returns the path of the proc macro server that's distributed with the toolchain that `config`
+/// says to use.
+fn sysroot_proc_macro_server(config: &Config) -> Result<PathBuf> {
+    let output = config
+        .rustc_command()
+        .args(["--print", "sysroot"])
+        .output()
+        .context("Failed to run rustc")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to get the sysroot from rustc: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let sysroot = String::from_utf8(output.stdout)?;
+    let path = Path::new(sysroot.trim()).join("libexec").join(format!(
+        "rust-analyzer-proc-macro-srv{}",
+        std::env::consts::EXE_SUFFIX
+    ));
+    if !path.exists() {
+        bail!("The toolchain has no proc macro server at {:?}", path);
+    }
+    Ok(path)
+}
+
+/// Loads the proc macros from the dylib at `path` into `server`.
+fn load_proc_macros(
+    server: &Option<Result<Arc<ProcMacroServer>, String>>,
+    path: &AbsPath,
+) -> ProcMacroLoadResult {
+    let server = match server {
+        Some(Ok(server)) => server,
+        Some(Err(error)) => return Err(format!("Proc macro server isn't available: {}", error)),
+        None => return Err("Proc macro server hasn't been started".to_owned()),
+    };
+    let macros = server
+        .list_macros(path.as_ref())
+        .map_err(|error| error.to_string())?;
+    Ok(macros
+        .into_iter()
+        .map(|(name, kind)| ProcMacro {
+            name: name.as_str().into(),
+            kind,
+            expander: Arc::new(Expander {
+                server: Arc::clone(server),
+                dylib_path: path.to_path_buf(),
+                name,
+            }),
         })
+        .collect())
+}
+
+/// Adapts a proc macro that the proc macro server has loaded to the interface that rust-analyzer's
+/// database expects.
+#[derive(Debug)]
+struct Expander {
+    server: Arc<ProcMacroServer>,
+    dylib_path: AbsPathBuf,
+    name: String,
+}
+
+impl ProcMacroExpander for Expander {
+    fn expand(
+        &self,
+        subtree: &ra_ap_tt::Subtree,
+        attrs: Option<&ra_ap_tt::Subtree>,
+        env: &Env,
+    ) -> Result<ra_ap_tt::Subtree, ProcMacroExpansionError> {
+        let env = env
+            .iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        self.server
+            .expand(self.dylib_path.as_ref(), &self.name, subtree, attrs, env)
     }
 }
 
+/// The code that a macro call expands to.
+#[derive(Debug)]
+pub(crate) struct MacroExpansion {
+    /// The name of the macro that was called.
+    pub(crate) name: String,
+    pub(crate) expansion: String,
+}
+
+/// A problem found by rust-analyzer's own diagnostics, as opposed to by running cargo check.
+#[derive(Debug)]
+pub(crate) struct Diagnostic {
+    pub(crate) message: String,
+    /// "error" or "warning", as per rustc's JSON output.
+    pub(crate) level: &'static str,
+    pub(crate) code: String,
+    pub(crate) start_offset: usize,
+    pub(crate) end_offset: usize,
+}
+
 /// If `pat` represents a variable that is being defined, then record it in `result` and return
 /// true.
 fn add_variable_for_pattern(
@@ -402,6 +856,140 @@ pub struct Completions {
 #[derive(Debug, Eq, PartialEq)]
 pub struct Completion {
     pub code: String,
+    pub kind: CompletionKind,
+    /// Additional information about the completion, e.g. the signature of a function or the type
+    /// of a field.
+    pub detail: Option<String>,
+    /// Documentation for the completed item, formatted as markdown.
+    pub documentation: Option<String>,
+    /// Use statements that are needed in order for `code` to resolve, e.g. `use
+    /// std::collections::HashMap;`. If the next code that's executed fails because the completed
+    /// name isn't in scope, and no other completion offered a different import for that name, the
+    /// import is added automatically.
+    pub imports: Vec<String>,
+}
+
+impl Completion {
+    /// Returns a completion of the specified kind that has no detail or documentation.
+    pub fn new(code: String, kind: CompletionKind) -> Completion {
+        Completion {
+            code,
+            kind,
+            detail: None,
+            documentation: None,
+            imports: Vec::new(),
+        }
+    }
+}
+
+/// What sort of thing a completion is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CompletionKind {
+    Method,
+    Field,
+    Function,
+    Module,
+    Keyword,
+    Snippet,
+    Variable,
+    Type,
+    Constant,
+    Macro,
+    /// An evcxr command, e.g. `:dep`.
+    Command,
+    Other,
+}
+
+impl CompletionKind {
+    fn from_item_kind(kind: ra_ide::CompletionItemKind) -> CompletionKind {
+        use ra_ide::CompletionItemKind as ItemKind;
+        use ra_ide::SymbolKind;
+        match kind {
+            ItemKind::Method => CompletionKind::Method,
+            ItemKind::Keyword => CompletionKind::Keyword,
+            ItemKind::Snippet => CompletionKind::Snippet,
+            ItemKind::Binding => CompletionKind::Variable,
+            ItemKind::BuiltinType => CompletionKind::Type,
+            ItemKind::SymbolKind(symbol) => match symbol {
+                SymbolKind::Field => CompletionKind::Field,
+                SymbolKind::Function => CompletionKind::Function,
+                SymbolKind::Module => CompletionKind::Module,
+                SymbolKind::Macro | SymbolKind::Attribute | SymbolKind::Derive => {
+                    CompletionKind::Macro
+                }
+                SymbolKind::Local | SymbolKind::ValueParam | SymbolKind::SelfParam => {
+                    CompletionKind::Variable
+                }
+                SymbolKind::Const | SymbolKind::Static | SymbolKind::ConstParam => {
+                    CompletionKind::Constant
+                }
+                SymbolKind::Struct
+                | SymbolKind::Enum
+                | SymbolKind::Union
+                | SymbolKind::Trait
+                | SymbolKind::TypeAlias
+                | SymbolKind::TypeParam
+                | SymbolKind::SelfType => CompletionKind::Type,
+                _ => CompletionKind::Other,
+            },
+            _ => CompletionKind::Other,
+        }
+    }
+}
+
+/// Information about an item in code, such as its signature and documentation, as would be shown
+/// when hovering over it in an editor.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Hover {
+    /// The information about the item, formatted as markdown.
+    pub markdown: String,
+    /// The range of code to which the information applies.
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+impl Hover {
+    /// Returns the information about the item as plain text. Code blocks are unwrapped, since
+    /// they're generally just the item's signature.
+    pub fn plain_text(&self) -> String {
+        let mut out = String::new();
+        for line in self.markdown.lines() {
+            if line.starts_with("```") {
+                continue;
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// The signature of a function or method whose arguments are being written.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SignatureHelp {
+    /// The signature of the function, e.g. `fn foo(a: i32, b: &str) -> bool`.
+    pub signature: String,
+    /// The byte range of each parameter within `signature`.
+    pub parameter_ranges: Vec<Range<usize>>,
+    /// The index of the parameter whose argument is being written, if any.
+    pub active_parameter: Option<usize>,
+    /// Documentation for the function, formatted as markdown.
+    pub documentation: Option<String>,
+}
+
+impl SignatureHelp {
+    /// Returns each parameter, e.g. `a: i32`.
+    pub fn parameters(&self) -> impl Iterator<Item = &str> {
+        self.parameter_ranges
+            .iter()
+            .map(|range| &self.signature[range.clone()])
+    }
+
+    /// Returns the parameter whose argument is being written, if any.
+    pub fn active_parameter_label(&self) -> Option<&str> {
+        self.parameters().nth(self.active_parameter?)
+    }
 }
 
 /// Returns whether this appears to be a valid type. Rust analyzer, when asked to emit code for some
@@ -439,7 +1027,7 @@ mod test {
     #[test]
     fn get_variable_types() -> Result<()> {
         let tmpdir = tempfile::tempdir()?;
-        let mut ra = RustAnalyzer::new(tmpdir.path())?;
+        let mut ra = RustAnalyzer::new(tmpdir.path(), None)?;
         ra.with_sysroot = false;
         std::fs::write(
             ra.cargo_toml_filename().to_path_buf(),
@@ -456,6 +1044,10 @@ mod test {
             r#"
             struct Foo<const I: usize> {}
             struct Point {x: u8, y: u8}
+            mod evcxr_cell;"#
+                .to_owned(),
+            r#"
+            use super::*;
             fn foo() {
                 let v1 = true;
                 let mut v1 = 42i32;
@@ -486,6 +1078,7 @@ mod test {
         assert_eq!(var_types["y2"].type_name, TypeName::named("u8"));
 
         ra.set_source(
+            "mod evcxr_cell;".to_owned(),
             r#"
             fn foo() {
                 let v1 = 1u16;
@@ -499,6 +1092,40 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn unchanged_files_are_not_reloaded() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut ra = RustAnalyzer::new(tmpdir.path(), None)?;
+        ra.with_sysroot = false;
+        std::fs::write(
+            ra.cargo_toml_filename().to_path_buf(),
+            r#"
+            [package]
+            name = "foo"
+            version = "0.1.0"
+
+            [lib]
+            "#,
+        )?;
+        let lib = "struct Foo;\nmod evcxr_cell;";
+        ra.set_source(
+            lib.to_owned(),
+            "use super::*;\nfn f() { let v1 = Foo; }".to_owned(),
+        )?;
+        // Simulate lib.rs being overwritten by something else. Since we don't change its contents,
+        // we shouldn't write it again, but we should still analyze what we previously set.
+        std::fs::write(ra.lib_file.path.as_path(), "")?;
+        ra.set_source(
+            lib.to_owned(),
+            "use super::*;\nfn f() { let v2 = Foo; }".to_owned(),
+        )?;
+        assert_eq!(std::fs::read_to_string(ra.lib_file.path.as_path())?, "");
+        let var_types = ra.top_level_variables("f");
+        assert_eq!(var_types["v2"].type_name, TypeName::named("Foo"));
+        assert!(!var_types.contains_key("v1"));
+        Ok(())
+    }
+
     #[test]
     fn test_is_type_valid() {
         assert!(is_type_valid("Vec<String>"));
@@ -13,6 +13,8 @@
 // limitations under the License.
 
 use evcxr::CommandContext;
+use evcxr::CompilationError;
+use evcxr::CompletionKind;
 use evcxr::Error;
 use evcxr::EvalContext;
 use evcxr::EvalContextOutputs;
@@ -22,6 +24,9 @@ use std::collections::HashSet;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::sync::Mutex;
 use tempfile;
 
@@ -424,6 +429,358 @@ fn crate_deps() {
     assert_eq!(outputs.content_by_mime_type, text_plain("42"));
 }
 
+#[test]
+fn load_local_crate() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r20() -> i32 {20}").unwrap();
+    let crate2 = TmpCrate::new("crate2", "pub fn r22() -> i32 {crate1::r20() + 2}").unwrap();
+    let mut cargo_toml = std::fs::read_to_string(crate2.tempdir.path().join("Cargo.toml")).unwrap();
+    cargo_toml.push_str(&format!(
+        "[dependencies]\ncrate1 = {{ path = \"{}\" }}\n",
+        crate1
+            .tempdir
+            .path()
+            .to_string_lossy()
+            .replace("\\", "\\\\")
+    ));
+    std::fs::write(crate2.tempdir.path().join("Cargo.toml"), cargo_toml).unwrap();
+    let outputs = eval_and_unwrap(
+        &mut e,
+        &format!(
+            ":load_crate {} --deps",
+            crate2.tempdir.path().to_string_lossy()
+        ),
+    );
+    assert!(outputs["text/plain"].starts_with("Loaded crate crate2 from "));
+    let outputs = e.execute("crate2::r22() + crate1::r20()").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+
+    // Paths may contain spaces and `--deps` may come first.
+    let parent = tempfile::tempdir().unwrap();
+    let crate_dir = parent.path().join("my crate");
+    std::fs::create_dir_all(crate_dir.join("src")).unwrap();
+    std::fs::write(
+        crate_dir.join("Cargo.toml"),
+        "[package]\nname = \"crate3\"\nversion = \"0.0.1\"\nedition = \"2018\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        crate_dir.join("src").join("lib.rs"),
+        "pub fn r2() -> i32 {2}",
+    )
+    .unwrap();
+    eval_and_unwrap(
+        &mut e,
+        &format!(":load_crate --deps {}", crate_dir.to_string_lossy()),
+    );
+    let outputs = e.execute("crate3::r2() + crate2::r22()").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("24"));
+}
+
+#[test]
+fn path_deps_reloaded_when_changed() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let crate0 = TmpCrate::new("crate0", "pub fn v() -> i32 {1}").unwrap();
+    let crate1 = TmpCrate::new("crate1", "pub fn r() -> i32 {crate0::v()} pub struct S;").unwrap();
+    let mut cargo_toml = std::fs::read_to_string(crate1.tempdir.path().join("Cargo.toml")).unwrap();
+    cargo_toml.push_str(&format!(
+        "[dependencies]\ncrate0 = {{ path = \"{}\" }}\n",
+        crate0
+            .tempdir
+            .path()
+            .to_string_lossy()
+            .replace('\\', "\\\\")
+    ));
+    std::fs::write(crate1.tempdir.path().join("Cargo.toml"), cargo_toml).unwrap();
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    eval_and_unwrap(&mut e, "let s = crate1::S;");
+    let outputs_before = e.execute("crate1::r()").unwrap();
+    assert_eq!(outputs_before.content_by_mime_type, text_plain("1"));
+    outputs.stdout.try_iter().for_each(drop);
+
+    // Changes to indirect path dependencies are picked up too.
+    std::fs::write(
+        crate0.tempdir.path().join("src").join("lib.rs"),
+        "pub fn v() -> i32 {42}",
+    )
+    .unwrap();
+    let outputs_after = e.execute("crate1::r()").unwrap();
+    assert_eq!(outputs_after.content_by_mime_type, text_plain("42"));
+    let stdout: Vec<String> = outputs.stdout.try_iter().collect();
+    assert!(stdout.contains(&"Source of `crate0` changed. Rebuilding.".to_owned()));
+    // `s` is from crate1, not crate0.
+    assert!(!stdout.iter().any(|line| line.starts_with("Warning")));
+
+    std::fs::write(
+        crate1.tempdir.path().join("src").join("lib.rs"),
+        "pub fn r() -> i32 {crate0::v() + 1} pub struct S;",
+    )
+    .unwrap();
+    assert_eq!(
+        e.execute("crate1::r()").unwrap().content_by_mime_type,
+        text_plain("43")
+    );
+    let stdout: Vec<String> = outputs.stdout.try_iter().collect();
+    assert!(stdout.contains(&"Source of `crate1` changed. Rebuilding.".to_owned()));
+    assert!(stdout.contains(
+        &"Warning: the following variables have types from `crate1` and may be invalid now \
+          that it has been rebuilt: s"
+            .to_owned()
+    ));
+    // Once rebuilt, we don't report the change again.
+    e.execute("crate1::r()").unwrap();
+    assert!(!outputs
+        .stdout
+        .try_iter()
+        .any(|line| line.starts_with("Source of")));
+}
+
+#[test]
+fn crate_deps_built_in_background() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    // A cell containing only a :dep command should return straight away and build the dependency
+    // in the background.
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    let outputs_after_dep = e.execute("crate1::r42()").unwrap();
+    assert_eq!(outputs_after_dep.content_by_mime_type, text_plain("42"));
+    let stdout: Vec<String> = outputs.stdout.try_iter().collect();
+    // Progress is reported while the build runs.
+    assert!(stdout
+        .iter()
+        .any(|line| line.starts_with("Building dependencies [") && line.ends_with("]: crate1")));
+    assert!(stdout.contains(&"Finished building dependencies.".to_owned()));
+}
+
+#[test]
+fn failed_background_build_removes_deps() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let broken = TmpCrate::new("broken_crate", "pub fn r42() -> i32 {").unwrap();
+    eval_and_unwrap(&mut e, &broken.dep_command(""));
+    // The failure is reported at the next eval and the dependency is removed, so after that, code
+    // that doesn't use it works again.
+    let error = e.execute("42").unwrap_err().to_string();
+    assert!(error.contains("The following dependencies have been removed: broken_crate"));
+    assert_eq!(eval_and_unwrap(&mut e, "40 + 2"), text_plain("42"));
+}
+
+#[test]
+fn list_resolved_deps() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    let outputs = eval_and_unwrap(&mut e, ":deps");
+    assert!(outputs["text/plain"].starts_with("crate1 = 0.0.1 ("));
+    assert!(outputs["text/html"].contains("<td>crate1</td><td>0.0.1</td>"));
+    let outputs = eval_and_unwrap(&mut e, ":tree");
+    assert!(outputs["text/plain"].contains("crate1 v0.0.1"));
+}
+
+#[test]
+fn search_local_registry_cache() {
+    let mut e = new_context();
+    assert!(e.execute(":search").is_err());
+    // Building evcxr requires regex, so it'll have been downloaded into the local registry cache.
+    let outputs = eval_and_unwrap(&mut e, ":search regex");
+    assert!(outputs["text/plain"].contains("regex: "));
+    assert!(outputs["text/html"].contains("<td>regex</td>"));
+    let outputs = eval_and_unwrap(&mut e, ":search evcxr_no_such_crate");
+    assert!(outputs["text/plain"].starts_with("No crates matching"));
+}
+
+#[test]
+fn auto_dep_from_local_registry_cache() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":auto_dep 1");
+    // Like regex, once_cell is needed to build evcxr, so it'll be in the local registry cache.
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            "*once_cell::sync::OnceCell::new().get_or_init(|| 42)"
+        ),
+        text_plain("42")
+    );
+    assert!(outputs
+        .stdout
+        .try_iter()
+        .any(|line| line.starts_with("Added dependency once_cell = ")));
+    // A crate that isn't in the cache still gives the original error.
+    assert!(e.execute("evcxr_no_such_crate::f()").is_err());
+}
+
+#[test]
+fn list_and_complete_dep_features() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    let mut cargo_toml = std::fs::read_to_string(crate1.tempdir.path().join("Cargo.toml")).unwrap();
+    cargo_toml
+        .push_str("[features]\ndefault = [\"apple\"]\napple = []\napricot = []\nbanana = []\n");
+    std::fs::write(crate1.tempdir.path().join("Cargo.toml"), cargo_toml).unwrap();
+    eval_and_unwrap(&mut e, &crate1.dep_command(r#"features = ["banana"]"#));
+    let outputs = eval_and_unwrap(&mut e, ":features crate1");
+    assert_eq!(
+        outputs["text/plain"],
+        "apple (default, enabled)\napricot\nbanana (enabled)\n"
+    );
+    let code = crate1.dep_command(r#"features = ["ap"#);
+    let code = code.trim_end_matches(" }");
+    let completions = e.completions(code, code.len()).unwrap();
+    assert_eq!(completions.start_offset, code.len() - "ap".len());
+    assert_eq!(
+        completions
+            .completions
+            .iter()
+            .map(|c| c.code.as_str())
+            .collect::<Vec<_>>(),
+        vec!["apple", "apricot"]
+    );
+    // Our tests run in offline mode, where we don't try to resolve dependencies from a registry.
+    let code = r#":dep regex = { version = "1", features = ["u"#;
+    assert!(e
+        .completions(code, code.len())
+        .unwrap()
+        .completions
+        .is_empty());
+}
+
+// :cdep isn't supported on Windows.
+#[cfg(not(windows))]
+#[test]
+fn link_c_dependency() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let c_dir = tempfile::tempdir().unwrap();
+    let c_file = c_dir.path().join("add.c");
+    std::fs::write(&c_file, "int evcxr_add(int a, int b) { return a + b; }\n").unwrap();
+    eval_and_unwrap(&mut e, &format!(":cdep {}", c_file.to_string_lossy()));
+    let outputs = e
+        .execute(
+            r#"
+            extern "C" {
+                fn evcxr_add(a: i32, b: i32) -> i32;
+            }
+            unsafe { evcxr_add(40, 2) }"#,
+        )
+        .unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    let remove = format!(":cdep --remove {}", c_file.to_string_lossy());
+    eval_and_unwrap(&mut e, &remove);
+    assert!(e.execute(&remove).is_err());
+}
+
+#[test]
+fn link_missing_native_lib() {
+    let mut e = new_context();
+    // A library is only added if we can link against it.
+    assert!(e.execute(":link evcxr_no_such_lib").is_err());
+    assert!(e.execute(":link --remove evcxr_no_such_lib").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, "40 + 2"), text_plain("42"));
+}
+
+#[test]
+fn define_proc_macro() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#":proc_macro evcxr_test_macros
+use proc_macro::TokenStream;
+
+#[proc_macro]
+pub fn forty_two(_input: TokenStream) -> TokenStream {
+    "42".parse().unwrap()
+}
+"#,
+    );
+    let outputs = e.execute("evcxr_test_macros::forty_two!()").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    // A broken redefinition is rejected and leaves the previous definition in place.
+    let error = e
+        .execute(
+            ":proc_macro evcxr_test_macros
+this isn't valid Rust",
+        )
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Failed to compile proc macro crate `evcxr_test_macros`"));
+    let outputs = e.execute("evcxr_test_macros::forty_two!() + 1").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("43"));
+}
+
+#[test]
+fn analyzer_expands_derive_macros() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#":proc_macro evcxr_test_derive
+use proc_macro::TokenStream;
+
+#[proc_macro_derive(Greet)]
+pub fn derive_greet(input: TokenStream) -> TokenStream {
+    let input = input.to_string();
+    let name = input
+        .split_whitespace()
+        .skip_while(|token| *token != "struct")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches(';');
+    format!("impl {} {{ pub fn greeting() -> String {{ \"hi\".to_owned() }} }}", name)
+        .parse()
+        .unwrap()
+}
+"#,
+    );
+    eval_and_unwrap(&mut e, "#[derive(evcxr_test_derive::Greet)] struct Foo;");
+    // Storing `g` requires that rust-analyzer knows its type, which it only does if it has expanded
+    // the derive.
+    eval_and_unwrap(&mut e, "let g = Foo::greeting();");
+    assert_eq!(eval_and_unwrap(&mut e, "g"), text_plain("\"hi\""));
+    assert!(simple_completions(&mut e, "Foo::gree").contains("greeting()"));
+}
+
+#[test]
+fn save_and_load_lockfile() {
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    let lock_dir = tempfile::tempdir().unwrap();
+    let lock_path = lock_dir.path().join("Cargo.lock");
+    let lock_path = lock_path.to_string_lossy();
+    {
+        let (mut e, _) = new_command_context_and_outputs();
+        eval_and_unwrap(&mut e, &crate1.dep_command(""));
+        eval_and_unwrap(&mut e, &format!(":lock save {}", lock_path));
+    }
+    let contents = std::fs::read_to_string(&*lock_path).unwrap();
+    assert!(contents.contains("name = \"crate1\""));
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, &format!(":lock load {}", lock_path));
+    let to_run = crate1.dep_command("") + "\ncrate1::r42()";
+    assert_eq!(eval_and_unwrap(&mut e, &to_run), text_plain("42"));
+    // A lockfile that cargo rejects isn't loaded and doesn't stop us from building.
+    let bad_lock_path = lock_dir.path().join("Bad.lock");
+    std::fs::write(&bad_lock_path, "not a lockfile").unwrap();
+    assert!(e
+        .execute(&format!(":lock load {}", bad_lock_path.to_string_lossy()))
+        .is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, "crate1::r42() + 1"),
+        text_plain("43")
+    );
+}
+
+#[test]
+fn build_config_changes_validated() {
+    let mut e = new_context();
+    // Cargo configuration that cargo can't parse is rejected straight away.
+    assert!(e
+        .execute(":cargo_config net.git-fetch-with-cli = = true")
+        .is_err());
+    eval_and_unwrap(&mut e, ":cargo_config net.git-fetch-with-cli = true");
+    assert!(e.execute(":cargo_config --remove net.retry").is_err());
+    eval_and_unwrap(&mut e, ":cargo_config --remove net.git-fetch-with-cli");
+    // Changes to how we build are checked by building, even when there's no code to run.
+    assert!(e.execute(":rustflags -C no-such-option").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, "40 + 2"), text_plain("42"));
+}
+
 #[test]
 fn crate_name_with_hyphens() {
     let (mut e, _) = new_command_context_and_outputs();
@@ -601,6 +958,97 @@ fn abort_and_restart() {
     assert_eq!(e.defined_item_names().next(), None);
 }
 
+fn phase_names(outputs: &evcxr::EvalOutputs) -> Vec<&str> {
+    outputs.phases.iter().map(|p| p.name.as_str()).collect()
+}
+
+#[test]
+fn compiled_code_reused_after_clear() {
+    let mut e = new_context();
+    let code = "let cache_test_value = 40 + 2; cache_test_value";
+    eval_and_unwrap(&mut e, ":clear");
+    let outputs = e.execute(code).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    eval_and_unwrap(&mut e, ":clear");
+    let outputs = e.execute(code).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    assert!(phase_names(&outputs).contains(&"Cache hit"));
+    assert!(!phase_names(&outputs).contains(&"Final compile"));
+}
+
+#[test]
+fn compiled_code_reused_by_new_context() {
+    // The cache outlives the session, so that re-running a notebook after restarting the kernel
+    // doesn't need to compile everything again.
+    let code = "let cache_test_value2 = 40 + 2; cache_test_value2";
+    let (mut first, _) = CommandContext::new_for_testing();
+    let outputs = first.execute(code).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    drop(first);
+    let (mut second, _) = CommandContext::new_for_testing();
+    let outputs = second.execute(code).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    assert!(phase_names(&outputs).contains(&"Cache hit"));
+    assert!(!phase_names(&outputs).contains(&"Final compile"));
+}
+
+// Process groups are a Unix concept.
+#[cfg(unix)]
+#[test]
+fn subprocess_in_own_process_group() {
+    let e = new_context();
+    let pid = e.process_handle().lock().unwrap().id() as libc::pid_t;
+    // So that pressing Ctrl-C in a terminal while building doesn't kill it.
+    assert_ne!(unsafe { libc::getpgid(pid) }, unsafe { libc::getpgid(0) });
+}
+
+#[test]
+fn interrupt_compilation() {
+    let mut e = new_context();
+    eval!(e, let interrupt_test_value = 41;);
+    // A dependency with a build script that doesn't finish until we tell it to, so that the build
+    // is certain to still be running when we interrupt it.
+    let slow = TmpCrate::new("slow_to_build", "pub fn one() -> i32 {1}").unwrap();
+    std::fs::write(
+        slow.tempdir.path().join("build.rs"),
+        r#"fn main() {
+            let done = std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("done");
+            for _ in 0..600 {
+                if done.exists() {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+        }"#,
+    )
+    .unwrap();
+    let handle = e.build_cancellation_handle();
+    let done = Arc::new(AtomicBool::new(false));
+    let canceller = {
+        let done = Arc::clone(&done);
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                if handle.cancel() {
+                    return true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            false
+        })
+    };
+    let result = e.execute(&format!(
+        "{}\nlet interrupted_value = interrupt_test_value + slow_to_build::one();",
+        slow.dep_command("")
+    ));
+    done.store(true, Ordering::SeqCst);
+    // Let any other builds of the dependency, e.g. for analysis, finish.
+    std::fs::write(slow.tempdir.path().join("done"), "").unwrap();
+    assert!(canceller.join().unwrap());
+    assert!(matches!(result, Err(Error::Interrupted)));
+    // Neither the committed state nor the subprocess should be affected by the interruption.
+    assert_eq!(eval!(e, interrupt_test_value + 1), text_plain("42"));
+}
+
 #[test]
 fn variable_assignment_compile_fail_then_use_statement() {
     let mut e = new_context();
@@ -765,6 +1213,172 @@ fn define_then_call_macro() {
     assert_eq!(eval!(e, foo!(2)), text_plain("42"));
 }
 
+#[test]
+fn hover_shows_signature_and_docs() {
+    let mut ctx = new_context();
+    let code = "/// Adds one to its argument.\nfn add_one(x: i32) -> i32 { x + 1 }\nadd_one(41)";
+    let position = code.rfind("add_one").unwrap() + 2;
+    let hover = ctx.hover(code, position).unwrap().unwrap();
+    assert!(hover.markdown.contains("fn add_one(x: i32) -> i32"));
+    assert!(hover.markdown.contains("Adds one to its argument."));
+    assert_eq!(hover.start_offset, code.rfind("add_one").unwrap());
+    assert_eq!(hover.end_offset, hover.start_offset + "add_one".len());
+    assert!(!hover.plain_text().contains("```"));
+}
+
+#[test]
+fn signature_help() {
+    let mut ctx = new_context();
+    let code = "/// Joins things.\nfn join_them(first: &str, second: u32) -> String { todo!() }\njoin_them(\"a\", ";
+    let help = ctx.signature_help(code, code.len()).unwrap().unwrap();
+    assert_eq!(
+        help.signature,
+        "fn join_them(first: &str, second: u32) -> String"
+    );
+    assert_eq!(
+        help.parameters().collect::<Vec<_>>(),
+        vec!["first: &str", "second: u32"]
+    );
+    assert_eq!(help.active_parameter_label(), Some("second: u32"));
+    assert_eq!(help.documentation.as_deref(), Some("Joins things."));
+    assert_eq!(ctx.signature_help("let x = 1;", 5).unwrap(), None);
+}
+
+#[test]
+fn expand_macros() {
+    let mut ctx = new_context();
+    eval_and_unwrap(
+        &mut ctx,
+        "macro_rules! add_one { ($e:expr) => { $e + 1 }; }",
+    );
+    let outputs = ctx.execute(":expand let x = add_one!(41);").unwrap();
+    let expanded = outputs.get("text/plain").unwrap();
+    assert!(expanded.starts_with("// line 1: add_one!\n"));
+    assert!(expanded.contains("41"));
+
+    let outputs = ctx
+        .execute(":expand\nlet a = 1;\nlet b = add_one!(a);")
+        .unwrap();
+    assert!(outputs
+        .get("text/plain")
+        .unwrap()
+        .starts_with("// line 2: add_one!\n"));
+    // Nothing should have been run.
+    assert!(ctx.execute("a").is_err());
+}
+
+#[test]
+fn emitted_function_code() {
+    let mut ctx = new_context();
+    eval_and_unwrap(&mut ctx, "fn evcxr_emit_test(x: u32) -> u32 { x * 3 }");
+    let emitted = |ctx: &mut CommandContext, command: &str| {
+        ctx.execute(&format!("{} evcxr_emit_test", command))
+            .unwrap()
+            .get("text/plain")
+            .unwrap()
+            .to_owned()
+    };
+    assert!(emitted(&mut ctx, ":asm").starts_with("ctx::evcxr_emit_test:"));
+    assert!(emitted(&mut ctx, ":mir").starts_with("fn evcxr_emit_test("));
+    let llvm_ir = emitted(&mut ctx, ":llvm_ir");
+    assert!(llvm_ir.contains("@ctx::evcxr_emit_test("));
+    assert!(llvm_ir.contains("with.overflow"));
+    assert!(ctx.execute(":asm evcxr_no_such_function").is_err());
+
+    // Flags passed to rustc should affect what's generated.
+    let outputs = ctx.execute(":rustflags -C overflow-checks=off").unwrap();
+    assert_eq!(
+        outputs.content_by_mime_type,
+        text_plain("rustflags: -C overflow-checks=off\n")
+    );
+    assert!(!emitted(&mut ctx, ":llvm_ir").contains("with.overflow"));
+}
+
+#[test]
+fn type_and_doc_commands() {
+    let (mut ctx, outputs) = new_command_context_and_outputs();
+    eval!(ctx, let values = vec![1u8, 2];);
+    let type_outputs = ctx
+        .execute(":type values.iter().map(|v| *v as u32).sum::<u32>()")
+        .unwrap();
+    assert_eq!(type_outputs.content_by_mime_type, text_plain("u32\n"));
+    let type_outputs = ctx.execute(":type vec![1u8]").unwrap();
+    assert_eq!(type_outputs.content_by_mime_type, text_plain("Vec<u8>\n"));
+    // Nothing should have been run.
+    assert_eq!(outputs.stdout.try_iter().count(), 0);
+
+    let docs = ctx.execute(":doc std::vec::Vec").unwrap();
+    assert!(docs.get("text/plain").unwrap().contains("struct Vec"));
+    assert!(docs.get("text/markdown").unwrap().contains("```rust"));
+}
+
+#[test]
+fn completion_metadata() {
+    let mut ctx = new_context();
+    let code = "/// Doubles x.\nfn double_it(x: i32) -> i32 { x * 2 }\nlet v = vec![1];\nv.pus";
+    let completions = ctx.completions(code, code.len()).unwrap();
+    let push = completions
+        .completions
+        .iter()
+        .find(|c| c.code == "push(value)")
+        .unwrap();
+    assert_eq!(push.kind, CompletionKind::Method);
+    assert!(push.detail.as_deref().unwrap().starts_with("fn(&mut self"));
+    assert!(push.documentation.is_some());
+
+    let code = code.replace("v.pus", "doub");
+    let completions = ctx.completions(&code, code.len()).unwrap();
+    let double_it = &completions.completions[0];
+    assert_eq!(double_it.kind, CompletionKind::Function);
+    assert_eq!(double_it.documentation.as_deref(), Some("Doubles x."));
+
+    let completions = ctx.completions(":dep", 4).unwrap();
+    assert!(completions
+        .completions
+        .iter()
+        .all(|c| c.kind == CompletionKind::Command));
+}
+
+#[test]
+fn import_on_the_fly_completion() {
+    let mut ctx = new_context();
+    let code = "let mut m = HashM";
+    let completions = ctx.completions(code, code.len()).unwrap();
+    let hash_map = completions
+        .completions
+        .iter()
+        .find(|c| c.code.starts_with("HashMap"))
+        .unwrap();
+    assert_eq!(
+        hash_map.imports,
+        vec!["use std::collections::HashMap;".to_owned()]
+    );
+    // Using the completion should bring in the import without the user needing to write it.
+    let outputs = ctx
+        .execute("let mut m = HashMap::new(); m.insert(1, 2); m.len()")
+        .unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("1"));
+    let outputs = ctx.execute("HashMap::<u8, u8>::new().len()").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("0"));
+    // Imports are only offered for the next code that's executed, even if it fails.
+    let code = "BTreeS";
+    ctx.completions(code, code.len()).unwrap();
+    assert!(ctx.execute("let x: u8 = 1000;").is_err());
+    assert!(ctx.execute("BTreeSet::<u8>::new().len()").is_err());
+    // If a name could come from more than one place, we don't guess.
+    let code = "Orderin";
+    let completions = ctx.completions(code, code.len()).unwrap();
+    assert!(
+        completions
+            .completions
+            .iter()
+            .filter(|c| c.code.starts_with("Ordering") && !c.imports.is_empty())
+            .count()
+            > 1
+    );
+    assert!(ctx.execute("let o = Ordering::Less;").is_err());
+}
+
 fn simple_completions(ctx: &mut CommandContext, code: &str) -> HashSet<String> {
     ctx.completions(code, code.len())
         .unwrap()
@@ -816,14 +1430,13 @@ fn code_completion() {
     let completions = ctx.completions(":de", 3).unwrap();
     assert_eq!(completions.start_offset, 0);
     assert_eq!(completions.end_offset, 3);
-    assert_eq!(
-        completions
-            .completions
-            .iter()
-            .map(|c| c.code.as_str())
-            .collect::<Vec<_>>(),
-        vec![":dep"]
-    );
+    let mut command_names = completions
+        .completions
+        .iter()
+        .map(|c| c.code.as_str())
+        .collect::<Vec<_>>();
+    command_names.sort_unstable();
+    assert_eq!(command_names, vec![":dep", ":deps"]);
 
     // Check that we get zero completions when expected.
     let code = code.replace("res", "asdfasdf");
@@ -907,8 +1520,12 @@ fn repeated_use_statements() {
 
 #[track_caller]
 fn check(ctx: &mut CommandContext, code: &str) -> Vec<String> {
+    error_spans(ctx.check(code).unwrap())
+}
+
+fn error_spans(errors: Vec<CompilationError>) -> Vec<String> {
     let mut out = Vec::new();
-    for err in ctx.check(code).unwrap() {
+    for err in errors {
         if let Some(spanned_message) = err.primary_spanned_message() {
             if let Some(span) = spanned_message.span {
                 out.push(format!(
@@ -936,6 +1553,16 @@ fn assert_no_errors(ctx: &mut CommandContext, code: &str) {
     assert_eq!(strs(&check(ctx, code)), Vec::<&str>::new());
 }
 
+#[test]
+fn fast_check_for_errors() {
+    let mut ctx = new_context();
+    let code = "struct Point { x: i32, y: i32 }\nlet p = Point { x: 1 };";
+    let errors = ctx.fast_check(code).unwrap();
+    assert!(errors[0].message().contains("missing structure fields"));
+    assert_eq!(strs(&error_spans(errors)), vec!["error 2:9-2:14"]);
+    assert_eq!(ctx.fast_check("let p = (1, 2);").unwrap().len(), 0);
+}
+
 #[test]
 fn check_for_errors() {
     let mut ctx = new_context();
@@ -44,7 +44,24 @@ define([
         }
     }
 
-    function lintText(text) {
+    // The results of cargo check for the most recently linted text. The kernel first sends the
+    // problems that rust-analyzer finds, which is fast, then the problems found by cargo check.
+    // When the latter arrive, we store them here and ask CodeMirror to lint again. Executing a cell
+    // can change what's valid in other cells, so the stored results are only used if nothing has
+    // been executed since they were requested.
+    let finalResults = { text: null, found: null };
+    let executions = 0;
+
+    function executionFinished() {
+        executions++;
+        finalResults = { text: null, found: null };
+    }
+
+    function lintText(text, options, cm) {
+        if (finalResults.text === text) {
+            return Promise.resolve(finalResults.found);
+        }
+        let requestedAfter = executions;
         return new Promise(function (resolve, reject) {
             let cargoCheckComm = Jupyter.notebook.kernel.comm_manager.new_comm('evcxr-cargo-check', {
                 code: text,
@@ -59,7 +76,12 @@ define([
                         message: problem.message,
                     });
                 }
-                resolve(found);
+                if (!msg.content.data.final) {
+                    resolve(found);
+                } else if (cm && cm.getValue() === text && requestedAfter === executions) {
+                    finalResults = { text: text, found: found };
+                    cm.performLint();
+                }
             });
         });
     }
@@ -70,6 +92,8 @@ define([
                 $('<link rel="stylesheet" type="text/css" />').attr('href', requireJs.toUrl('./lint.css'))
             )
             events.on('create.Cell', cellCreated);
+            events.on('finished_execute.CodeCell', executionFinished);
+            events.on('kernel_restarting.Kernel', executionFinished);
             initExistingCells();
             CodeMirror.registerHelper("lint", "rust", lintText);
         }
@@ -21,6 +21,7 @@ use ariadne::sources;
 use colored::*;
 use crossbeam_channel::Select;
 use evcxr::CommandContext;
+use evcxr::CompletionKind;
 use evcxr::Theme;
 use json::JsonValue;
 use std::collections::HashMap;
@@ -95,11 +96,15 @@ impl Server {
         let (mut context, outputs) = CommandContext::new()?;
         context.execute(":load_config")?;
         let process_handle = context.process_handle();
+        let build_cancellation = context.build_cancellation_handle();
         let context = Arc::new(std::sync::Mutex::new(context));
         {
             let server = server.clone();
             tokio::spawn(async move {
-                if let Err(error) = server.handle_control(control_socket, process_handle).await {
+                if let Err(error) = server
+                    .handle_control(control_socket, process_handle, build_cancellation)
+                    .await
+                {
                     eprintln!("control error: {error:?}");
                 }
             });
@@ -198,10 +203,11 @@ impl Server {
             let context = Arc::clone(context);
             let server = self.clone();
             let (eval_result, message) = tokio::task::spawn_blocking(move || {
+                let build_progress_displayed = std::cell::Cell::new(false);
                 let eval_result = context.lock().unwrap().execute_with_callbacks(
                     message.code(),
-                    &mut evcxr::EvalCallbacks {
-                        input_reader: &|input_request| {
+                    &mut evcxr::EvalCallbacks::default()
+                        .with_input_reader(&|input_request| {
                             server.tokio_handle.block_on(async {
                                 server
                                     .request_input(
@@ -212,8 +218,21 @@ impl Server {
                                     .await
                                     .unwrap_or_default()
                             })
-                        },
-                    },
+                        })
+                        .with_build_progress(&|progress| {
+                            server.tokio_handle.block_on(async {
+                                if let Err(error) = server
+                                    .send_build_progress(
+                                        &message,
+                                        &progress,
+                                        &build_progress_displayed,
+                                    )
+                                    .await
+                                {
+                                    eprintln!("build progress error: {}", error);
+                                }
+                            })
+                        }),
                 );
                 (eval_result, message)
             })
@@ -311,6 +330,48 @@ impl Server {
             .map(|value| value.to_owned())
     }
 
+    /// Displays the progress of a build. The first update for an execution request creates a
+    /// display, subsequent updates replace its contents. `displayed` records whether we've
+    /// created the display yet.
+    async fn send_build_progress(
+        &self,
+        current_request: &JupyterMessage,
+        progress: &evcxr::BuildProgress,
+        displayed: &std::cell::Cell<bool>,
+    ) -> Result<()> {
+        let text = if progress.crates_built < progress.total_crates {
+            format!(
+                "Building [{}/{}]: {}",
+                progress.crates_built, progress.total_crates, progress.last_crate
+            )
+        } else if displayed.get() {
+            // The build is done, so clear the progress display.
+            String::new()
+        } else {
+            // Only the final crate needed building, which isn't worth displaying progress for.
+            return Ok(());
+        };
+        let message_type = if displayed.replace(true) {
+            "update_display_data"
+        } else {
+            "display_data"
+        };
+        current_request
+            .new_message(message_type)
+            .with_content(object! {
+                "data" => object! {
+                    "text/plain" => text,
+                },
+                "metadata" => object! {},
+                "transient" => object! {
+                    "display_id" => format!("evcxr_build_progress_{}", current_request.message_id()),
+                },
+            })
+            .send(&mut *self.iopub.lock().await)
+            .await?;
+        Ok(())
+    }
+
     async fn handle_shell<S: zeromq::SocketRecv + zeromq::SocketSend>(
         self,
         mut connection: Connection<S>,
@@ -385,6 +446,18 @@ impl Server {
                 },
             );
             reply.send(connection).await?;
+        } else if message.message_type() == "inspect_request" {
+            let reply = message.new_reply().with_content(
+                match handle_inspect_request(context, message).await {
+                    Ok(response_content) => response_content,
+                    Err(error) => object! {
+                        "status" => "error",
+                        "ename" => error.to_string(),
+                        "evalue" => "",
+                    },
+                },
+            );
+            reply.send(connection).await?;
         } else if message.message_type() == "history_request" {
             // We don't yet support history requests, but we don't want to print
             // a message in jupyter console.
@@ -402,6 +475,7 @@ impl Server {
         mut self,
         mut connection: Connection<zeromq::RouterSocket>,
         process_handle: Arc<std::sync::Mutex<std::process::Child>>,
+        build_cancellation: evcxr::BuildCancellationHandle,
     ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&mut connection).await?;
@@ -409,7 +483,13 @@ impl Server {
                 "shutdown_request" => self.signal_shutdown().await,
                 "interrupt_request" => {
                     let process_handle = process_handle.clone();
+                    let build_cancellation = build_cancellation.clone();
                     tokio::task::spawn_blocking(move || {
+                        // If we're in the middle of building, then just stop the build. The
+                        // subprocess isn't running any user code, so there's no need to kill it.
+                        if build_cancellation.cancel() {
+                            return;
+                        }
                         if let Err(error) = process_handle.lock().unwrap().kill() {
                             eprintln!("Failed to restart subprocess: {}", error);
                         }
@@ -588,7 +668,39 @@ async fn comm_open(
         let context = Arc::clone(context);
         tokio::spawn(async move {
             if let Some(code) = message.data()["code"].as_str() {
-                let data = cargo_check(code.to_owned(), context).await;
+                // We first send the problems that rust-analyzer can find, since that's fast. Once
+                // cargo check has finished, we send a second message with its problems.
+                for check_kind in [CheckKind::RustAnalyzer, CheckKind::Cargo] {
+                    let mut data = check(code.to_owned(), Arc::clone(&context), check_kind).await;
+                    data["final"] = (check_kind == CheckKind::Cargo).into();
+                    let response_content = object! {
+                        "comm_id" => message.comm_id(),
+                        "data" => data,
+                    };
+                    message
+                        .new_message("comm_msg")
+                        .without_parent_header()
+                        .with_content(response_content)
+                        .send(&mut *iopub.lock().await)
+                        .await
+                        .unwrap();
+                }
+            }
+            message
+                .comm_close_message()
+                .send(&mut *iopub.lock().await)
+                .await
+                .unwrap();
+        });
+        Ok(())
+    } else if message.target_name() == "evcxr-signature-help" {
+        let context = Arc::clone(context);
+        tokio::spawn(async move {
+            if let (Some(code), Some(cursor_pos)) = (
+                message.data()["code"].as_str(),
+                message.data()["cursor_pos"].as_usize(),
+            ) {
+                let data = signature_help(code.to_owned(), cursor_pos, context).await;
                 let response_content = object! {
                     "comm_id" => message.comm_id(),
                     "data" => data,
@@ -617,9 +729,57 @@ async fn comm_open(
     }
 }
 
-async fn cargo_check(code: String, context: Arc<std::sync::Mutex<CommandContext>>) -> JsonValue {
+/// Returns the signature of the function call at `cursor_pos`, which is in graphemes, as expected
+/// by Jupyter.
+async fn signature_help(
+    code: String,
+    cursor_pos: usize,
+    context: Arc<std::sync::Mutex<CommandContext>>,
+) -> JsonValue {
+    let help = tokio::task::spawn_blocking(move || {
+        let position = grapheme_offset_to_byte_offset(&code, cursor_pos);
+        context
+            .lock()
+            .unwrap()
+            .signature_help(&code, position)
+            .unwrap_or_default()
+    })
+    .await
+    .unwrap_or_default();
+    match help {
+        Some(help) => object! {
+            "found" => true,
+            "signature" => help.signature.clone(),
+            "parameters" => help.parameters().collect::<Vec<_>>(),
+            "active_parameter" => help.active_parameter,
+            "documentation" => help.documentation.clone(),
+        },
+        None => object! {
+            "found" => false,
+        },
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CheckKind {
+    /// Only report problems that rust-analyzer finds. Fast, but finds fewer problems.
+    RustAnalyzer,
+    /// Run cargo check.
+    Cargo,
+}
+
+async fn check(
+    code: String,
+    context: Arc<std::sync::Mutex<CommandContext>>,
+    check_kind: CheckKind,
+) -> JsonValue {
     let problems = tokio::task::spawn_blocking(move || {
-        context.lock().unwrap().check(&code).unwrap_or_default()
+        let mut context = context.lock().unwrap();
+        let problems = match check_kind {
+            CheckKind::RustAnalyzer => context.fast_check(&code),
+            CheckKind::Cargo => context.check(&code),
+        };
+        problems.unwrap_or_default()
     })
     .await
     .unwrap_or_default();
@@ -704,17 +864,82 @@ async fn handle_completion_request(
             code,
             grapheme_offset_to_byte_offset(code, message.cursor_pos()),
         )?;
-        let matches: Vec<String> = completions
-            .completions
-            .into_iter()
-            .map(|completion| completion.code)
-            .collect();
+        let cursor_start = byte_offset_to_grapheme_offset(code, completions.start_offset)?;
+        let cursor_end = byte_offset_to_grapheme_offset(code, completions.end_offset)?;
+        let mut matches = Vec::new();
+        let mut typed_matches = Vec::new();
+        for completion in completions.completions {
+            // JupyterLab shows the type and signature of each match if they're provided in this
+            // (experimental) format.
+            let mut typed_match = object! {
+                "start" => cursor_start,
+                "end" => cursor_end,
+                "text" => completion.code.clone(),
+                "type" => jupyter_completion_type(completion.kind),
+                "signature" => completion.detail.unwrap_or_default(),
+            };
+            if let Some(documentation) = completion.documentation {
+                typed_match["documentation"] = documentation.into();
+            }
+            typed_matches.push(typed_match);
+            matches.push(completion.code);
+        }
         Ok(object! {
             "status" => "ok",
             "matches" => matches,
-            "cursor_start" => byte_offset_to_grapheme_offset(code, completions.start_offset)?,
-            "cursor_end" => byte_offset_to_grapheme_offset(code, completions.end_offset)?,
-            "metadata" => object!{},
+            "cursor_start" => cursor_start,
+            "cursor_end" => cursor_end,
+            "metadata" => object!{
+                "_jupyter_types_experimental" => typed_matches,
+            },
+        })
+    })
+    .await?
+}
+
+/// Returns the name used by IPython for the kind of completion that most closely matches `kind`.
+fn jupyter_completion_type(kind: CompletionKind) -> &'static str {
+    match kind {
+        CompletionKind::Method | CompletionKind::Function => "function",
+        CompletionKind::Field | CompletionKind::Variable => "instance",
+        CompletionKind::Module => "module",
+        CompletionKind::Keyword => "keyword",
+        CompletionKind::Snippet => "snippet",
+        CompletionKind::Type => "class",
+        CompletionKind::Constant => "constant",
+        CompletionKind::Macro => "macro",
+        CompletionKind::Command => "magic",
+        _ => "text",
+    }
+}
+
+async fn handle_inspect_request(
+    context: &Arc<std::sync::Mutex<CommandContext>>,
+    message: JupyterMessage,
+) -> Result<JsonValue> {
+    let context = Arc::clone(context);
+    tokio::task::spawn_blocking(move || {
+        let code = message.code();
+        let hover = context.lock().unwrap().hover(
+            code,
+            grapheme_offset_to_byte_offset(code, message.cursor_pos()),
+        )?;
+        Ok(match hover {
+            Some(hover) => object! {
+                "status" => "ok",
+                "found" => true,
+                "data" => object! {
+                    "text/plain" => hover.plain_text(),
+                    "text/markdown" => hover.markdown,
+                },
+                "metadata" => object!{},
+            },
+            None => object! {
+                "status" => "ok",
+                "found" => false,
+                "data" => object!{},
+                "metadata" => object!{},
+            },
         })
     })
     .await?
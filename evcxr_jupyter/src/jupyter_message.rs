@@ -143,6 +143,10 @@ impl JupyterMessage {
         self.header["msg_type"].as_str().unwrap_or("")
     }
 
+    pub(crate) fn message_id(&self) -> &str {
+        self.header["msg_id"].as_str().unwrap_or("")
+    }
+
     pub(crate) fn code(&self) -> &str {
         self.content["code"].as_str().unwrap_or("")
     }
@@ -15,9 +15,11 @@
 use anyhow::Result;
 use ariadne::sources;
 use colored::*;
+use evcxr::BuildCancellationHandle;
 use evcxr::CommandContext;
 use evcxr::CompilationError;
 use evcxr::Error;
+use evcxr::EvalCallbacks;
 use evcxr::Theme;
 use evcxr_repl::BgInitMutex;
 use evcxr_repl::EvcxrRustylineHelper;
@@ -32,16 +34,24 @@ use rustyline::KeyEvent;
 use rustyline::Modifiers;
 use rustyline::Movement;
 use rustyline::Word;
+use std::cell::Cell;
 use std::fs;
 use std::io;
 use std::sync::Arc;
+use std::sync::Mutex;
 use structopt::StructOpt;
 
 const PROMPT: &str = ">> ";
 
+type InterruptHandles = (BuildCancellationHandle, Arc<Mutex<std::process::Child>>);
+
 struct Repl {
     command_context: Arc<BgInitMutex<Result<CommandContext, Error>>>,
     ide_mode: bool,
+    /// Allows a build to be cancelled when the user presses Ctrl-C, together with a handle to our
+    /// subprocess, which we need to kill if there was no build to cancel. Only set while we're
+    /// executing code.
+    interrupt_handles: Arc<Mutex<Option<InterruptHandles>>>,
 }
 
 fn send_output<T: io::Write + Send + 'static>(
@@ -86,16 +96,61 @@ impl Repl {
             Ok(command_context)
         };
         let command_context = Arc::new(BgInitMutex::new(initialize));
+        let interrupt_handles: Arc<Mutex<Option<InterruptHandles>>> = Arc::default();
+        {
+            let interrupt_handles = Arc::clone(&interrupt_handles);
+            // If we fail to set the handler, then Ctrl-C will just terminate the process, as it
+            // always used to.
+            let _ = ctrlc::set_handler(move || {
+                if let Some((build_cancellation, process_handle)) =
+                    &*interrupt_handles.lock().unwrap()
+                {
+                    if build_cancellation.cancel() {
+                        return;
+                    }
+                    // Our subprocess is in its own process group, so it doesn't get the SIGINT.
+                    let _ = process_handle.lock().unwrap().kill();
+                }
+                std::process::exit(130);
+            });
+        }
         Repl {
             command_context,
             ide_mode,
+            interrupt_handles,
         }
     }
     fn execute(&mut self, to_run: &str) -> Result<(), Error> {
+        let progress_displayed = Cell::new(false);
         let execution_result = match &mut *self.command_context.lock() {
-            Ok(context) => context.execute(to_run),
+            Ok(context) => {
+                *self.interrupt_handles.lock().unwrap() = Some((
+                    context.build_cancellation_handle(),
+                    context.process_handle(),
+                ));
+                let result = context.execute_with_callbacks(
+                    to_run,
+                    &mut EvalCallbacks::default().with_build_progress(&|progress| {
+                        progress_displayed.set(true);
+                        eprint!(
+                            "\r\x1b[K{}",
+                            format!(
+                                "Building [{}/{}]: {}",
+                                progress.crates_built, progress.total_crates, progress.last_crate
+                            )
+                            .blue()
+                        );
+                    }),
+                );
+                *self.interrupt_handles.lock().unwrap() = None;
+                result
+            }
             Err(error) => return Err(error.clone()),
         };
+        if progress_displayed.get() {
+            // Clear the progress line.
+            eprint!("\r\x1b[K");
+        }
         let success = match execution_result {
             Ok(output) => {
                 if let Some(text) = output.get("text/plain") {
@@ -22,6 +22,7 @@ use evcxr::Error;
 use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
+use rustyline::hint::Hint;
 use rustyline::hint::Hinter;
 use rustyline::validate::ValidationContext;
 use rustyline::validate::ValidationResult;
@@ -44,7 +45,34 @@ impl EvcxrRustylineHelper {
 // Have to implement a bunch of traits as mostly noop...
 
 impl Hinter for EvcxrRustylineHelper {
-    type Hint = String;
+    type Hint = SignatureHint;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<SignatureHint> {
+        // Only show signatures when the user has just started writing an argument, since computing
+        // them on every keystroke would slow down typing.
+        if pos != line.len() || !line.trim_end().ends_with(&['(', ','][..]) {
+            return None;
+        }
+        let help = match &mut *self.command_context.lock() {
+            Ok(command_context) => command_context.signature_help(line, pos).ok()??,
+            Err(_) => return None,
+        };
+        Some(SignatureHint(format!("  {}", help.signature)))
+    }
+}
+
+/// The signature of the function whose arguments are being typed. Unlike a normal hint, this
+/// isn't something that can be accepted, it's just displayed.
+pub struct SignatureHint(String);
+
+impl Hint for SignatureHint {
+    fn display(&self) -> &str {
+        &self.0
+    }
+
+    fn completion(&self) -> Option<&str> {
+        None
+    }
 }
 
 impl Completer for EvcxrRustylineHelper {
@@ -84,6 +112,10 @@ impl Highlighter for EvcxrRustylineHelper {
     ) -> Cow<'b, str> {
         prompt.yellow().to_string().into()
     }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        hint.dimmed().to_string().into()
+    }
 }
 
 impl Validator for EvcxrRustylineHelper {